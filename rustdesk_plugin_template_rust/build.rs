@@ -0,0 +1,3 @@
+fn main() {
+    plugin_build::build("plugin.toml");
+}
@@ -1,61 +1,80 @@
 use crate::{
-    cstr_to_string,
-    desc::{self, get_desc},
-    early_return_if_true, early_return_value,
+    args_to_string,
+    context::{get_context, update_context, Context, HostFeatures, Plugin},
+    cstr_to_string, cstr_to_string_bounded,
+    desc::Permission,
+    diag,
     errno::*,
+    error::error_return,
+    guard,
     handler::*,
-    init::get_init_data,
-    PluginReturn,
+    mem,
+    method::MsgCall,
+    policy,
+    stats::{self, MsgGetStats, StatsFormat},
+    NativeReturnValue, PluginReturn,
+};
+use plugin_common::serde_json;
+use std::{
+    ffi::{c_char, c_void},
+    ptr::null_mut,
+    sync::Arc,
+    time::Instant,
 };
-use plugin_common::{libc, serde_json};
-use std::ffi::{c_char, c_void};
 
 macro_rules! early_call_return_if_true {
     ($e:expr, $code: ident, $($arg:tt)*) => {
         if $e {
-            return PluginReturn::new($code, &format_args!($($arg)*).to_string());
+            return error_return($code, &format_args!($($arg)*).to_string(), None);
         }
     };
 }
 
+// The max lengths of the method and the peer id, including the trailing 0.
+const MAX_METHOD_LEN: usize = 256;
+const MAX_PEER_LEN: usize = 1024;
+
 #[inline]
-fn is_method(method: *const c_char, target: &[u8]) -> bool {
-    target == unsafe { std::slice::from_raw_parts(method as *const u8, target.len()) }
+fn is_method(method: &str, target: &[u8]) -> bool {
+    target.strip_suffix(b"\0").unwrap_or(target) == method.as_bytes()
 }
 
-fn process_return(plugin_id: &str, peer: String, ret: HandlerRet) -> PluginReturn {
-    for msg in ret.msgs.to_config.into_iter() {
+fn process_msgs(plugin_id: &str, peer: &str, msgs: Msgs) {
+    for msg in msgs.to_config.into_iter() {
         let _r = call_msg_cb(
-            peer.clone(),
+            peer.to_owned(),
             MSG_TO_CONFIG_TARGET,
             plugin_id.to_owned(),
             msg.as_bytes(),
         );
     }
 
-    for msg in ret.msgs.to_peer.into_iter() {
+    for msg in msgs.to_peer.into_iter() {
         let _r = call_msg_cb(
-            peer.clone(),
+            peer.to_owned(),
             MSG_TO_PEER_TARGET,
             plugin_id.to_owned(),
             msg.as_bytes(),
         );
     }
 
-    for msg in ret.msgs.to_ui.into_iter() {
+    for msg in msgs.to_ui.into_iter() {
         let mut content = MSG_TO_UI_FLUTTER_CHANNEL_REMOTE.to_le_bytes().to_vec();
         content.extend(serde_json::to_string(&msg).unwrap().as_bytes());
         let _r = call_msg_cb(
-            peer.clone(),
+            peer.to_owned(),
             MSG_TO_UI_TARGET,
             plugin_id.to_owned(),
             &content,
         );
     }
+}
 
+fn process_return(plugin_id: &str, peer: &str, ret: HandlerRet) -> PluginReturn {
+    process_msgs(plugin_id, peer, ret.msgs);
     match ret.code {
         ERR_SUCCESS => PluginReturn::success(),
-        _ => PluginReturn::new(ret.code, &ret.msg),
+        _ => error_return(ret.code, &ret.msg, ret.details.as_deref()),
     }
 }
 
@@ -67,57 +86,179 @@ pub fn plugin_call(
     out: *mut *mut c_void,
     out_len: *mut usize,
 ) -> PluginReturn {
-    early_call_return_if_true!(
-        get_init_data().lock().unwrap().is_none(),
-        ERR_PLUGIN_MSG_INIT,
-        "Plugin must be initialized before calling any other functions"
-    );
+    // The output is written with its length.
+    if !out.is_null() && out_len.is_null() {
+        return error_return(ERR_CALL_INVALID_ARGS, "The output length is null", None);
+    }
+    let method_name = cstr_to_string_bounded(method, MAX_METHOD_LEN).ok();
+    // Answered before any check, the diagnostics are needed most when the checks fail.
+    if method_name
+        .as_deref()
+        .is_some_and(|m| is_method(m, METHOD_GET_DIAGNOSTICS))
+    {
+        return handle_get_diagnostics(out, out_len);
+    }
 
-    early_call_return_if_true!(
-        get_handler().is_none(),
-        ERR_PLUGIN_MSG_INIT,
-        "Plugin handler must be set before calling any other functions"
-    );
+    let _pending = diag::PendingCall::new();
+    let ret = handle_call(method, peer, args, len, out, out_len);
+    if !ret.is_success() {
+        let peer = cstr_to_string_bounded(peer, MAX_PEER_LEN).unwrap_or_default();
+        let msg = cstr_to_string(ret.msg).unwrap_or_default();
+        diag::record_error(
+            method_name.as_deref().unwrap_or_default(),
+            &peer,
+            ret.code,
+            &msg,
+        );
+    }
+    ret
+}
 
+fn handle_call(
+    method: *const c_char,
+    peer: *const c_char,
+    args: *const c_void,
+    len: usize,
+    out: *mut *mut c_void,
+    out_len: *mut usize,
+) -> PluginReturn {
+    // The init data and the plugins are set together by `init`.
+    // Each call takes its own snapshot, so a re-entrant call from inside a host callback
+    // neither blocks nor is affected by a `reset` or `clear` in between.
+    let context = get_context();
     early_call_return_if_true!(
-        get_desc().is_none(),
+        context.is_none(),
         ERR_PLUGIN_MSG_INIT,
-        "Plugin desc must be set before calling any other functions"
+        "Plugin must be initialized before calling any other functions"
     );
+    let ctx = context.unwrap();
+    if let Some(ret) = guard::poisoned_return() {
+        return ret;
+    }
 
-    let is_null = method.is_null();
-    early_call_return_if_true!(is_null, ERR_CALL_INVALID_METHOD, "method is null");
+    let method = match cstr_to_string_bounded(method, MAX_METHOD_LEN) {
+        Ok(method) => method,
+        Err(e) => return error_return(ERR_CALL_INVALID_METHOD, &format!("method: {}", e), None),
+    };
+    let method = &method as &str;
 
-    let peer = match cstr_to_string(peer) {
+    let peer = match cstr_to_string_bounded(peer, MAX_PEER_LEN) {
         Ok(peer) => peer,
         Err(e) => {
-            return PluginReturn::new(
+            return error_return(
                 ERR_CALL_INVALID_PEER,
                 &format!("parse remote peer id: {:?}", e),
+                None,
             )
         }
     };
+    let _span = enter_call(method, &peer);
+
+    let start = Instant::now();
+    let ret = dispatch(&ctx, method, &peer, args, len, out, out_len);
+    let bytes_out = if out.is_null() || unsafe { (*out).is_null() } {
+        0
+    } else {
+        unsafe { *out_len }
+    };
+    stats::record_call(method, &peer, ret.code, start.elapsed(), len, bytes_out);
+    ret
+}
 
-    let d = get_desc().as_ref().unwrap();
+fn dispatch(
+    ctx: &Context,
+    method: &str,
+    peer: &str,
+    args: *const c_void,
+    len: usize,
+    out: *mut *mut c_void,
+    out_len: *mut usize,
+) -> PluginReturn {
+    if !out.is_null() {
+        // The output is only valid if it is set by the handler.
+        unsafe {
+            *out = null_mut();
+            *out_len = 0;
+        }
+    }
+    if is_method(method, METHOD_HANDLE_LISTEN_EVENT) {
+        return handle_msg_listen(ctx, peer, args, len);
+    }
+    if is_method(method, METHOD_SET_HOST_FEATURES) {
+        return handle_set_host_features(args, len);
+    }
+    if is_method(method, METHOD_GET_STATS) {
+        return handle_get_stats(args, len, out, out_len);
+    }
 
-    let ret = if is_method(method, METHOD_HANDLE_UI) {
-        handle_msg_ui(d, args, len)
+    let (plugin, ret) = if is_method(method, METHOD_HANDLE_UI) {
+        match parse_msg_ui(args, len).and_then(|m| Ok((find_plugin(ctx, &m.id)?, m))) {
+            Ok((plugin, msg_ui)) => {
+                let _span = enter_plugin(plugin);
+                (Some(plugin), handle_msg_ui(ctx, plugin, msg_ui))
+            }
+            Err(ret) => (None, ret),
+        }
     } else if is_method(method, METHOD_HANDLE_PEER) {
-        handle_msg_peer(d, args, len, out, out_len)
-    } else if is_method(method, METHOD_HANDLE_LISTEN_EVENT) {
-        handle_msg_listen(d, &peer, args, len)
-    } else {
-        HandlerRet {
-            code: ERR_CALL_NOT_SUPPORTED_METHOD,
-            msg: format!(
-                "Unsupported call of '{:?}'",
-                unsafe { std::ffi::CStr::from_ptr(method) }.to_str()
+        match parse_msg_peer(args, len).and_then(|m| Ok((find_plugin(ctx, &m.id)?, m))) {
+            Ok((plugin, msg_peer)) => {
+                let _span = enter_plugin(plugin);
+                (
+                    Some(plugin),
+                    handle_msg_peer(plugin, peer, &msg_peer.method, args, len, out, out_len),
+                )
+            }
+            Err(ret) => (None, ret),
+        }
+    } else if !ctx.plugins().iter().any(|p| p.methods().contains(method)) {
+        // Checked before parsing the args, the args of an unknown method may be anything.
+        (
+            None,
+            err_ret(
+                ERR_CALL_NOT_SUPPORTED_METHOD,
+                format!("Unsupported call of '{}'", method),
             ),
-            msgs: Msgs::default(),
+        )
+    } else {
+        match parse_msg_call(args, len).and_then(|m| Ok((find_plugin(ctx, &m.id)?, m))) {
+            Ok((plugin, msg_call)) => {
+                let _span = enter_plugin(plugin);
+                (
+                    Some(plugin),
+                    handle_msg_call(plugin, method, peer, msg_call, out, out_len),
+                )
+            }
+            Err(ret) => (None, ret),
         }
     };
 
-    process_return(&d.id, peer, ret)
+    process_return(plugin.map(|p| p.id()).unwrap_or_default(), peer, ret)
+}
+
+/// Enter the span of the call, the logs of the call are prefixed by the method and the peer.
+#[cfg(feature = "tracing")]
+fn enter_call(method: &str, peer: &str) -> plugin_common::tracing::span::EnteredSpan {
+    plugin_common::tracing::info_span!("plugin_call", method, peer).entered()
+}
+
+/// The placeholder of the span guard without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+struct NoSpan;
+
+#[cfg(not(feature = "tracing"))]
+fn enter_call(_method: &str, _peer: &str) -> NoSpan {
+    NoSpan
+}
+
+/// Enter the span of the plugin handling the call.
+#[cfg(feature = "tracing")]
+fn enter_plugin(plugin: &Plugin) -> plugin_common::tracing::span::EnteredSpan {
+    plugin_common::tracing::info_span!("plugin", id = plugin.id()).entered()
+}
+
+#[cfg(not(feature = "tracing"))]
+fn enter_plugin(_plugin: &Plugin) -> NoSpan {
+    NoSpan
 }
 
 enum PeerIdOrRet {
@@ -125,93 +266,335 @@ enum PeerIdOrRet {
     Ret(HandlerRet),
 }
 
-fn get_local_peer_id() -> PeerIdOrRet {
-    if let Some(data) = get_init_data().lock().unwrap().as_ref() {
-        let id_ptr = (data.cbs.get_id)();
-        match cstr_to_string(id_ptr) {
-            Ok(id) => {
-                unsafe {
-                    libc::free(id_ptr as _);
-                }
-                PeerIdOrRet::PeerId(id)
-            }
-            Err(..) => PeerIdOrRet::Ret(HandlerRet {
-                code: ERR_PLUGIN_MSG_GET_LOCAL_PEER_ID,
-                msg: "parse local peer id".to_owned(),
-                msgs: Msgs::default(),
-            }),
+fn get_local_peer_id(ctx: &Context) -> PeerIdOrRet {
+    let id_ptr = (ctx.callbacks().get_id)();
+    match cstr_to_string(id_ptr) {
+        Ok(id) => {
+            mem::free(id_ptr as _);
+            PeerIdOrRet::PeerId(id)
         }
-    } else {
-        PeerIdOrRet::Ret(HandlerRet {
-            code: ERR_PLUGIN_MSG_INIT,
-            msg: "Callbacks must be set before calling any other functions".to_owned(),
-            msgs: Msgs::default(),
+        Err(..) => PeerIdOrRet::Ret(HandlerRet::new(
+            ERR_PLUGIN_MSG_GET_LOCAL_PEER_ID,
+            "parse local peer id",
+        )),
+    }
+}
+
+#[inline]
+fn err_ret(code: i32, msg: String) -> HandlerRet {
+    HandlerRet::new(code, msg)
+}
+
+fn find_plugin<'a>(ctx: &'a Context, id: &str) -> Result<&'a Arc<Plugin>, HandlerRet> {
+    ctx.plugin(id)
+        .ok_or_else(|| err_ret(ERR_PEER_ID_MISMATCH, format!("Unknown plugin id {}", id)))
+}
+
+fn parse_msg_ui(args: *const c_void, len: usize) -> Result<MsgFromUi, HandlerRet> {
+    let content = args_to_string(args, len).map_err(|e| {
+        err_ret(
+            ERR_CALL_INVALID_ARGS,
+            format!("Failed to parse args '{:?}'", e),
+        )
+    })?;
+    serde_json::from_str::<MsgFromUi>(&content).map_err(|e| {
+        err_ret(
+            ERR_CALL_INVALID_ARGS,
+            format!("Failed to parse {} '{:?}'", content, e),
+        )
+    })
+}
+
+fn parse_msg_peer(args: *const c_void, len: usize) -> Result<MsgPeer, HandlerRet> {
+    MsgPeer::from_args(args, len).map_err(|e| {
+        err_ret(
+            ERR_CALL_INVALID_ARGS,
+            format!("Failed to parse args '{:?}'", e),
+        )
+    })
+}
+
+fn parse_msg_call(args: *const c_void, len: usize) -> Result<MsgCall, HandlerRet> {
+    args_to_string(args, len)
+        .and_then(|s| Ok(serde_json::from_str::<MsgCall>(&s)?))
+        .map_err(|e| {
+            err_ret(
+                ERR_CALL_INVALID_ARGS,
+                format!("Failed to parse args '{}'", e),
+            )
         })
+}
+
+/// Call the custom method, the output is written to `out` in JSON if `out` is not null.
+fn handle_msg_call(
+    plugin: &Plugin,
+    method: &str,
+    peer: &str,
+    msg_call: MsgCall,
+    out: *mut *mut c_void,
+    out_len: *mut usize,
+) -> HandlerRet {
+    let output = match plugin
+        .methods()
+        .call(method, plugin.desc(), peer, msg_call.content)
+    {
+        Some(Ok(output)) => output,
+        Some(Err(e)) => return e.into(),
+        None => {
+            return err_ret(
+                ERR_CALL_NOT_SUPPORTED_METHOD,
+                format!("Unsupported call of '{}'", method),
+            )
+        }
+    };
+    if !out.is_null() {
+        let mut s = output.to_string();
+        s.push('\0');
+        mem::bytes_to_out(s.as_bytes(), out, out_len);
     }
+    HandlerRet::success()
 }
 
-fn handle_msg_ui(d: &desc::Desc, args: *const c_void, _len: usize) -> HandlerRet {
-    let content = early_return_value!(
-        cstr_to_string(args as _),
-        ERR_CALL_INVALID_ARGS,
-        "parse args"
-    );
-    let msg_ui = early_return_value!(
-        serde_json::from_str::<MsgFromUi>(&content as _),
-        ERR_CALL_INVALID_ARGS,
-        "parse {}",
-        content,
-    );
-    early_return_if_true!(msg_ui.id != d.id, ERR_CALL_INVALID_ARGS, "id mismatch");
-    let local_peer_id = match get_local_peer_id() {
+fn handle_msg_ui(ctx: &Context, plugin: &Plugin, msg_ui: MsgFromUi) -> HandlerRet {
+    let local_peer_id = match get_local_peer_id(ctx) {
         PeerIdOrRet::PeerId(peer_id) => peer_id,
         PeerIdOrRet::Ret(ret) => return ret,
     };
-    (*get_handler().as_ref().unwrap()).handle_ui_event(d, local_peer_id, msg_ui)
+    plugin
+        .handler()
+        .handle_ui_event(plugin.desc(), local_peer_id, msg_ui)
 }
 
+/// Listen events are broadcast to all the plugins.
+///
+/// The first failure is returned if any.
 fn handle_msg_listen(
-    d: &desc::Desc,
+    ctx: &Context,
     remote_peer_id: &str,
     args: *const c_void,
-    _len: usize,
-) -> HandlerRet {
-    let event = early_return_value!(
-        MsgListenEvent::from_cstr(args as _),
-        ERR_CALL_INVALID_ARGS,
-        "parse args"
-    );
-    let local_peer_id = match get_local_peer_id() {
+    len: usize,
+) -> PluginReturn {
+    let event = match MsgListenEvent::from_args(args, len) {
+        Ok(event) => event,
+        Err(e) => {
+            return error_return(
+                ERR_CALL_INVALID_ARGS,
+                &format!("Failed to parse args '{:?}'", e),
+                None,
+            )
+        }
+    };
+    diag::on_listen_event(remote_peer_id, &event.event);
+    let local_peer_id = match get_local_peer_id(ctx) {
         PeerIdOrRet::PeerId(peer_id) => peer_id,
-        PeerIdOrRet::Ret(ret) => return ret,
+        PeerIdOrRet::Ret(ret) => return error_return(ret.code, &ret.msg, ret.details.as_deref()),
     };
-    (*get_handler().as_ref().unwrap()).handle_listen_event(d, local_peer_id, remote_peer_id, event)
+    let mut failure = None;
+    for plugin in ctx.plugins() {
+        let _span = enter_plugin(plugin);
+        let ret = plugin.handler().handle_listen_event(
+            plugin.desc(),
+            local_peer_id.clone(),
+            remote_peer_id,
+            MsgListenEvent {
+                event: event.event.clone(),
+            },
+        );
+        process_msgs(plugin.id(), remote_peer_id, ret.msgs);
+        if ret.code != ERR_SUCCESS && failure.is_none() {
+            failure = Some((ret.code, ret.msg, ret.details));
+        }
+    }
+    match failure {
+        Some((code, msg, details)) => error_return(code, &msg, details.as_deref()),
+        None => PluginReturn::success(),
+    }
 }
 
+/// Call the handler of the peer method if the policy of the method accepts the remote peer.
 fn handle_msg_peer(
-    d: &desc::Desc,
+    plugin: &Plugin,
+    peer: &str,
+    method: &str,
     args: *const c_void,
     len: usize,
     out: *mut *mut c_void,
     out_len: *mut usize,
 ) -> HandlerRet {
+    if let Err(e) = policy::check(plugin.desc(), plugin.policies(), method, peer) {
+        plugin_common::warn!("Refused the peer method of {}, {}", plugin.id(), e);
+        return e.into();
+    }
     if !out.is_null() {
-        (*get_handler().as_ref().unwrap()).handle_client_event(d, args, len, out, out_len)
+        plugin
+            .handler()
+            .handle_client_event(plugin.desc(), args, len, out, out_len)
+    } else {
+        plugin
+            .handler()
+            .handle_server_event(plugin.desc(), args, len)
+    }
+}
+
+fn handle_set_host_features(args: *const c_void, len: usize) -> PluginReturn {
+    match args_to_string(args, len).and_then(|s| Ok(serde_json::from_str::<HostFeatures>(&s)?)) {
+        Ok(features) => {
+            update_context(|c| c.with_host_features(features));
+            PluginReturn::success()
+        }
+        Err(e) => error_return(
+            ERR_CALL_INVALID_ARGS,
+            &format!("Failed to parse host features '{}'", e),
+            None,
+        ),
+    }
+}
+
+/// The metrics of all the plugins of the library, written to `out`.
+fn handle_get_stats(
+    args: *const c_void,
+    len: usize,
+    out: *mut *mut c_void,
+    out_len: *mut usize,
+) -> PluginReturn {
+    let msg = if args.is_null() {
+        Ok(MsgGetStats::default())
+    } else {
+        args_to_string(args, len).and_then(|s| {
+            if s.trim().is_empty() {
+                Ok(MsgGetStats::default())
+            } else {
+                Ok(serde_json::from_str::<MsgGetStats>(&s)?)
+            }
+        })
+    };
+    let msg = match msg {
+        Ok(msg) => msg,
+        Err(e) => {
+            return error_return(
+                ERR_CALL_INVALID_ARGS,
+                &format!("Failed to parse args '{}'", e),
+                None,
+            )
+        }
+    };
+    if out.is_null() {
+        return error_return(
+            ERR_CALL_INVALID_ARGS,
+            "No output buffer for the stats",
+            None,
+        );
+    }
+    let stats = stats::get_stats();
+    let mut s = match msg.format {
+        StatsFormat::Json => stats.to_json(),
+        StatsFormat::Prometheus => stats.to_prometheus(),
+    };
+    s.push('\0');
+    mem::bytes_to_out(s.as_bytes(), out, out_len);
+    PluginReturn::success()
+}
+
+fn handle_get_diagnostics(out: *mut *mut c_void, out_len: *mut usize) -> PluginReturn {
+    if out.is_null() {
+        return error_return(
+            ERR_CALL_INVALID_ARGS,
+            "No output buffer for the diagnostics",
+            None,
+        );
+    }
+    let mut s = diag::get_diagnostics().to_json();
+    s.push('\0');
+    mem::bytes_to_out(s.as_bytes(), out, out_len);
+    PluginReturn::success()
+}
+
+/// Get the config of the plugin, each plugin has its own config namespace.
+///
+/// peer: The peer id, empty for the shared config.
+pub fn get_conf(plugin_id: &str, peer: &str, key: &str) -> Option<String> {
+    let ctx = get_context()?;
+    let peer = format!("{}\0", peer);
+    let id = format!("{}\0", plugin_id);
+    let key = format!("{}\0", key);
+    let ptr = (ctx.callbacks().get_conf)(peer.as_ptr() as _, id.as_ptr() as _, key.as_ptr() as _);
+    if ptr.is_null() {
+        return None;
+    }
+    let value = cstr_to_string(ptr).ok();
+    mem::free(ptr as _);
+    value
+}
+
+/// The permission required to send the content to the target.
+///
+/// `None` if the target is unknown, the host will refuse it.
+fn required_permission(target: &[u8], content: &[u8]) -> Option<Permission> {
+    if target == MSG_TO_PEER_TARGET {
+        Some(Permission::SendToPeer)
+    } else if target == MSG_TO_UI_TARGET {
+        Some(Permission::ShowUi)
+    } else if target == MSG_TO_EXT_SUPPORT_TARGET {
+        Some(Permission::ExtSupport)
+    } else if target == MSG_TO_CONFIG_TARGET {
+        let config_type = serde_json::from_slice::<serde_json::Value>(content)
+            .ok()
+            .and_then(|v| v.get("type")?.as_str().map(|s| s.to_owned()));
+        match config_type.as_deref() {
+            Some(CONFIG_TYPE_PEER) => Some(Permission::WritePeerConfig),
+            // Unknown config types are treated as shared config.
+            _ => Some(Permission::WriteSharedConfig),
+        }
     } else {
-        (*get_handler().as_ref().unwrap()).handle_server_event(d, args, len)
+        None
     }
 }
 
+fn check_permission(
+    plugin_id: &str,
+    permission: Permission,
+    action: &str,
+) -> Result<(), (i32, String)> {
+    let ctx = match get_context() {
+        Some(ctx) => ctx,
+        None => return Ok(()),
+    };
+    let msg = match ctx.plugin(plugin_id) {
+        Some(plugin) if plugin.desc().has_permission(permission) => return Ok(()),
+        Some(_) => format!(
+            "Plugin {} is not permitted to {}, '{:?}' is not declared",
+            plugin_id, action, permission
+        ),
+        None => format!("Unknown plugin id {}, refuse to {}", plugin_id, action),
+    };
+    plugin_common::error!("{}", &msg);
+    Err((ERR_PERMISSION_NOT_DECLARED, msg))
+}
+
+/// Send the message to the host.
+///
+/// The callback is called on a snapshot of the context and no lock is held,
+/// the host may call back into the plugin synchronously from inside the callback.
 pub fn call_msg_cb(
     mut peer: String,
     target: &[u8],
     mut id: String,
     content: &[u8],
 ) -> (i32, String) {
-    if let Some(data) = get_init_data().lock().unwrap().as_ref() {
+    if let Some(permission) = required_permission(target, content) {
+        let action = format!(
+            "send message to '{}'",
+            String::from_utf8_lossy(target.strip_suffix(b"\0").unwrap_or(target))
+        );
+        if let Err(e) = check_permission(&id, permission, &action) {
+            return e;
+        }
+    }
+
+    if let Some(ctx) = get_context() {
         peer.push('\0');
         id.push('\0');
-        let mut ret = (data.cbs.msg)(
+        let mut ret = (ctx.callbacks().msg)(
             peer.as_ptr() as _,
             target.as_ptr() as _,
             id.as_ptr() as _,
@@ -221,6 +604,9 @@ pub fn call_msg_cb(
         if ret.is_success() {
             (ERR_SUCCESS, "".to_owned())
         } else {
+            stats::record_msg_failure(&String::from_utf8_lossy(
+                target.strip_suffix(b"\0").unwrap_or(target),
+            ));
             ret.get_code_msg()
         }
     } else {
@@ -228,9 +614,139 @@ pub fn call_msg_cb(
     }
 }
 
+/// Call the native method of librustdesk, `Permission::Native` is required.
+///
+/// plugin_id: The id of the plugin which makes the call.
+/// method, json: utf8 strings, json must be a valid json.
+/// raw: the binary data of this call.
+pub fn call_native(
+    plugin_id: &str,
+    method: &str,
+    json: &str,
+    raw: Option<&[u8]>,
+) -> Result<NativeReturnValue, (i32, String)> {
+    check_permission(
+        plugin_id,
+        Permission::Native,
+        &format!("call native '{}'", method),
+    )?;
+
+    let native = match get_context() {
+        Some(ctx) => ctx.callbacks().native,
+        None => {
+            return Err((
+                ERR_PLUGIN_MSG_INIT,
+                "Callbacks must be set before calling any other functions".to_owned(),
+            ))
+        }
+    };
+    let mut method = method.to_owned();
+    method.push('\0');
+    let mut json = json.to_owned();
+    json.push('\0');
+    let (raw, raw_len) = match raw {
+        Some(raw) => (raw.as_ptr() as *const c_void, raw.len()),
+        None => (std::ptr::null(), 0),
+    };
+    Ok(native(
+        method.as_ptr() as _,
+        json.as_ptr() as _,
+        raw,
+        raw_len,
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::desc;
+
+    #[test]
+    fn test_is_method() {
+        assert!(is_method("handle_ui", METHOD_HANDLE_UI));
+        assert!(!is_method("handle_u", METHOD_HANDLE_UI));
+        assert!(!is_method("handle_ui2", METHOD_HANDLE_UI));
+        assert!(!is_method("", METHOD_HANDLE_LISTEN_EVENT));
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let code = |r: Result<MsgFromUi, HandlerRet>| r.err().map(|r| r.code);
+        let msg = br#"{"id":"a","name":"b","location":"host|main|settings|plugin","key":"k","value":"v","action":""}"#;
+        assert_eq!(
+            parse_msg_ui(msg.as_ptr() as _, msg.len())
+                .ok()
+                .map(|m| m.id),
+            Some("a".to_owned())
+        );
+        // Truncated by `len`, the bytes after are not read.
+        assert_eq!(
+            code(parse_msg_ui(msg.as_ptr() as _, msg.len() - 1)),
+            Some(ERR_CALL_INVALID_ARGS)
+        );
+        assert_eq!(
+            code(parse_msg_ui(std::ptr::null(), 0)),
+            Some(ERR_CALL_INVALID_ARGS)
+        );
+        assert_eq!(
+            parse_msg_peer(b"\xff".as_ptr() as _, 1)
+                .err()
+                .map(|r| r.code),
+            Some(ERR_CALL_INVALID_ARGS)
+        );
+    }
+
+    #[test]
+    fn test_null_out_len() {
+        let mut out = std::ptr::null_mut();
+        let mut ret = plugin_call(
+            c"get_diagnostics".as_ptr(),
+            c"".as_ptr(),
+            std::ptr::null(),
+            0,
+            &mut out,
+            std::ptr::null_mut(),
+        );
+        assert_eq!(ret.get_code_msg().0, ERR_CALL_INVALID_ARGS);
+        assert!(out.is_null());
+    }
+
+    #[test]
+    fn test_required_permission() {
+        let shared = MsgToConfig::new_string(
+            CONFIG_TYPE_SHARED.to_owned(),
+            "key".to_owned(),
+            desc::CONFIG_VALUE_TRUE.to_owned(),
+            None,
+        );
+        let peer = MsgToConfig::new_string(
+            CONFIG_TYPE_PEER.to_owned(),
+            "key".to_owned(),
+            desc::CONFIG_VALUE_TRUE.to_owned(),
+            None,
+        );
+        assert_eq!(
+            required_permission(MSG_TO_CONFIG_TARGET, shared.as_bytes()),
+            Some(Permission::WriteSharedConfig)
+        );
+        assert_eq!(
+            required_permission(MSG_TO_CONFIG_TARGET, peer.as_bytes()),
+            Some(Permission::WritePeerConfig)
+        );
+        assert_eq!(
+            required_permission(MSG_TO_PEER_TARGET, b""),
+            Some(Permission::SendToPeer)
+        );
+        assert_eq!(
+            required_permission(MSG_TO_UI_TARGET, b""),
+            Some(Permission::ShowUi)
+        );
+        assert_eq!(
+            required_permission(MSG_TO_EXT_SUPPORT_TARGET, b""),
+            Some(Permission::ExtSupport)
+        );
+        assert_eq!(required_permission(b"unknown\0", b""), None);
+    }
 
     #[test]
     fn test_event_ui_to_string() {
@@ -0,0 +1,252 @@
+//! The state of the plugin.
+//!
+//! The context is set by `init`, replaced by `reset` and removed by `clear`.
+//! The host may call the plugin from several threads while the context is changed,
+//! so the context is immutable and shared by `Arc`. Callers take a snapshot by
+//! `get_context()` and the lock is never held while calling handlers or the host.
+
+use crate::{
+    desc::Desc, handler::Handler, init::InitData, method::Methods, migration::Migrations,
+    policy::Policies, Callbacks,
+};
+use plugin_common::{lazy_static::lazy_static, semver::Version, serde_derive::Deserialize};
+use std::sync::{Arc, PoisonError, RwLock};
+
+lazy_static! {
+    static ref CONTEXT: RwLock<Option<Arc<Context>>> = Default::default();
+}
+
+/// A logical plugin, a shared library may contain several plugins with different ids.
+pub struct Plugin {
+    desc: Arc<Desc>,
+    handler: Arc<dyn Handler>,
+    methods: Methods,
+    migrations: Migrations,
+    policies: Policies,
+}
+
+impl Plugin {
+    pub(crate) fn new(desc: Desc, handler: Box<dyn Handler>) -> Self {
+        Self::from_parts(Arc::new(desc), handler)
+    }
+
+    /// The plugin with the same desc and the new handler.
+    pub(crate) fn with_handler(&self, handler: Box<dyn Handler>) -> Self {
+        Self::from_parts(self.desc.clone(), handler)
+    }
+
+    fn from_parts(desc: Arc<Desc>, handler: Box<dyn Handler>) -> Self {
+        let mut methods = Methods::default();
+        handler.register_methods(&mut methods);
+        let mut migrations = Migrations::default();
+        handler.register_migrations(&mut migrations);
+        let mut policies = Policies::from_desc(&desc);
+        handler.register_policies(&mut policies);
+        Self {
+            desc,
+            handler: Arc::from(handler),
+            methods,
+            migrations,
+            policies,
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> &str {
+        &self.desc.id
+    }
+
+    #[inline]
+    pub fn desc(&self) -> &Desc {
+        &self.desc
+    }
+
+    #[inline]
+    pub fn handler(&self) -> &dyn Handler {
+        self.handler.as_ref()
+    }
+
+    #[inline]
+    pub(crate) fn shared_handler(&self) -> Arc<dyn Handler> {
+        self.handler.clone()
+    }
+
+    #[inline]
+    pub fn methods(&self) -> &Methods {
+        &self.methods
+    }
+
+    #[inline]
+    pub fn migrations(&self) -> &Migrations {
+        &self.migrations
+    }
+
+    #[inline]
+    pub fn policies(&self) -> &Policies {
+        &self.policies
+    }
+}
+
+/// The optional features announced by the host by `METHOD_SET_HOST_FEATURES`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct HostFeatures {
+    /// `PluginReturn.msg` of the errors is `ErrorDetails` in JSON.
+    pub json_errors: bool,
+}
+
+pub struct Context {
+    init_data: InitData,
+    // `None` if the host version is not a semver, only allowed if no plugin declares a range.
+    host_version: Option<Version>,
+    host_features: HostFeatures,
+    // The registry of the logical plugins, in the order of registration.
+    plugins: Vec<Arc<Plugin>>,
+}
+
+impl Context {
+    pub(crate) fn new(
+        init_data: InitData,
+        host_version: Option<Version>,
+        plugins: Vec<Plugin>,
+    ) -> Self {
+        Self {
+            init_data,
+            host_version,
+            host_features: HostFeatures::default(),
+            plugins: plugins.into_iter().map(Arc::new).collect(),
+        }
+    }
+
+    /// Create a new context with the new init data, the plugins are kept.
+    pub(crate) fn with_init_data(
+        &self,
+        init_data: InitData,
+        host_version: Option<Version>,
+    ) -> Self {
+        Self {
+            init_data,
+            host_version,
+            host_features: self.host_features.clone(),
+            plugins: self.plugins.clone(),
+        }
+    }
+
+    /// Create a new context with the new host features.
+    pub(crate) fn with_host_features(&self, host_features: HostFeatures) -> Self {
+        Self {
+            init_data: self.init_data.clone(),
+            host_version: self.host_version.clone(),
+            host_features,
+            plugins: self.plugins.clone(),
+        }
+    }
+
+    /// Create a new context with the plugin replaced, matched by the id.
+    pub(crate) fn with_plugin(&self, plugin: Plugin) -> Self {
+        let plugin = Arc::new(plugin);
+        Self {
+            init_data: self.init_data.clone(),
+            host_version: self.host_version.clone(),
+            host_features: self.host_features.clone(),
+            plugins: self
+                .plugins
+                .iter()
+                .map(|p| {
+                    if p.id() == plugin.id() {
+                        plugin.clone()
+                    } else {
+                        p.clone()
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[inline]
+    pub fn init_data(&self) -> &InitData {
+        &self.init_data
+    }
+
+    #[inline]
+    pub fn callbacks(&self) -> &Callbacks {
+        &self.init_data.cbs
+    }
+
+    /// The parsed version of the host, handlers can use it to gate features.
+    ///
+    /// `None` if the host version is not a semver, the init fails then if a plugin declares
+    /// `min_host_version` or `max_host_version`.
+    #[inline]
+    pub fn host_version(&self) -> Option<&Version> {
+        self.host_version.as_ref()
+    }
+
+    #[inline]
+    pub fn host_features(&self) -> &HostFeatures {
+        &self.host_features
+    }
+
+    #[inline]
+    pub fn plugins(&self) -> &[Arc<Plugin>] {
+        &self.plugins
+    }
+
+    pub fn plugin(&self, id: &str) -> Option<&Arc<Plugin>> {
+        self.plugins.iter().find(|p| p.id() == id)
+    }
+}
+
+/// Get the snapshot of the current context, `None` if the plugin is not initialized.
+pub fn get_context() -> Option<Arc<Context>> {
+    CONTEXT
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+pub(crate) fn set_context(context: Option<Context>) -> Option<Arc<Context>> {
+    std::mem::replace(
+        &mut *CONTEXT.write().unwrap_or_else(PoisonError::into_inner),
+        context.map(Arc::new),
+    )
+}
+
+/// Replace the current context with the one derived from it, nothing is done if not initialized.
+///
+/// The lock is held while calling `f`, it must not call the handlers or the host.
+/// Returns whether the context is replaced.
+pub(crate) fn update_context(f: impl FnOnce(&Context) -> Context) -> bool {
+    let mut lock = CONTEXT.write().unwrap_or_else(PoisonError::into_inner);
+    match lock.as_ref() {
+        Some(context) => {
+            *lock = Some(Arc::new(f(context)));
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{errno::ERR_PLUGIN_PANIC, guard::catch_panic};
+
+    #[test]
+    fn test_poisoned_context() {
+        std::thread::spawn(|| {
+            let _lock = CONTEXT.write().unwrap();
+            panic!("poison the context");
+        })
+        .join()
+        .unwrap_err();
+        assert!(CONTEXT.is_poisoned());
+
+        // The panic is still caught, the error is built without panicking again.
+        let mut ret = catch_panic("client_call", || panic!("panic after poisoned"));
+        let (code, msg) = ret.get_code_msg();
+        assert_eq!(code, ERR_PLUGIN_PANIC);
+        assert!(msg.contains("panic after poisoned"), "{}", msg);
+        get_context();
+    }
+}
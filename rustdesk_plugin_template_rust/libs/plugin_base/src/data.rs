@@ -0,0 +1,93 @@
+//! The local data directory of the plugins.
+//!
+//! Each plugin has its own directory, `<base>/<plugin id>`. The base is the
+//! `RUSTDESK_PLUGIN_DATA_DIR` environment variable if set, otherwise the per-user data
+//! directory of RustDesk on the platform.
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// The environment variable overriding the base directory, for the tests or the portable setups.
+pub const ENV_DATA_DIR: &str = "RUSTDESK_PLUGIN_DATA_DIR";
+
+/// The data directory of the plugin, it is not created here.
+///
+/// `None` if the plugin id is not a valid file name or the base directory is unknown.
+pub fn data_dir(plugin_id: &str) -> Option<PathBuf> {
+    if !is_valid_dir_name(plugin_id) {
+        return None;
+    }
+    Some(base_dir()?.join(plugin_id))
+}
+
+/// The directory of the local log files of the plugin.
+pub fn log_dir(plugin_id: &str) -> Option<PathBuf> {
+    Some(data_dir(plugin_id)?.join("logs"))
+}
+
+fn base_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os(ENV_DATA_DIR).filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir));
+    }
+    platform_base_dir().map(|d| d.join("plugins"))
+}
+
+#[cfg(windows)]
+fn platform_base_dir() -> Option<PathBuf> {
+    Some(
+        Path::new(&env::var_os("APPDATA")?)
+            .join("RustDesk")
+            .join("data"),
+    )
+}
+
+#[cfg(target_os = "macos")]
+fn platform_base_dir() -> Option<PathBuf> {
+    Some(
+        Path::new(&env::var_os("HOME")?)
+            .join("Library")
+            .join("Application Support")
+            .join("RustDesk"),
+    )
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn platform_base_dir() -> Option<PathBuf> {
+    match env::var_os("XDG_DATA_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => Some(Path::new(&dir).join("rustdesk")),
+        None => Some(
+            Path::new(&env::var_os("HOME")?)
+                .join(".local")
+                .join("share")
+                .join("rustdesk"),
+        ),
+    }
+}
+
+/// The id is used as a directory name, it must not escape the base directory.
+fn is_valid_dir_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_name() {
+        assert!(is_valid_dir_name("TemplateTestIdRust"));
+        assert!(is_valid_dir_name("com.example.plugin-1"));
+        assert!(!is_valid_dir_name(""));
+        assert!(!is_valid_dir_name(".."));
+        assert!(!is_valid_dir_name("a/b"));
+        assert!(!is_valid_dir_name("a\\b"));
+        assert!(data_dir("../x").is_none());
+    }
+}
@@ -1,9 +1,190 @@
-use plugin_common::serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::policy::Policy;
+use plugin_common::{
+    anyhow::anyhow,
+    bail, semver,
+    serde_derive::{Deserialize, Serialize},
+    ResultType,
+};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+};
 
 pub const CONFIG_VALUE_TRUE: &str = "1";
 pub const CONFIG_VALUE_FALSE: &str = "0";
 
+const LOCATION_SEPARATOR: char = '|';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Host,
+    Client,
+}
+
+impl Side {
+    pub const ALL: [Side; 2] = [Side::Host, Side::Client];
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Side::Host => "host",
+            Side::Client => "client",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Window {
+    Main,
+    Remote,
+    Cm,
+    Transfer,
+    Forward,
+}
+
+impl Window {
+    pub const ALL: [Window; 5] = [
+        Window::Main,
+        Window::Remote,
+        Window::Cm,
+        Window::Transfer,
+        Window::Forward,
+    ];
+
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Window::Main => "main",
+            Window::Remote => "remote",
+            Window::Cm => "cm",
+            Window::Transfer => "transfer",
+            Window::Forward => "forward",
+        }
+    }
+}
+
+/// The location of the ui element, "side|window|placement", e.g. "host|main|settings|plugin".
+///
+/// The placement is one or more segments of `[a-z0-9_-]`, separated by '|'.
+/// It is serialized to the pipe-separated string on the wire.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LocationPath {
+    side: Side,
+    window: Window,
+    placement: Cow<'static, str>,
+}
+
+impl LocationPath {
+    pub fn new(side: Side, window: Window, placement: &str) -> ResultType<Self> {
+        if !is_valid_placement(placement) {
+            bail!("invalid placement '{}'", placement);
+        }
+        Ok(Self {
+            side,
+            window,
+            placement: Cow::Owned(placement.to_owned()),
+        })
+    }
+
+    /// Const constructor for the static locations.
+    ///
+    /// Panics if the placement is invalid, which fails the build if used in a const context.
+    pub const fn from_static(side: Side, window: Window, placement: &'static str) -> Self {
+        assert!(is_valid_placement(placement), "invalid placement");
+        Self {
+            side,
+            window,
+            placement: Cow::Borrowed(placement),
+        }
+    }
+
+    #[inline]
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    #[inline]
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    #[inline]
+    pub fn placement(&self) -> &str {
+        &self.placement
+    }
+}
+
+const fn is_valid_placement(placement: &str) -> bool {
+    let bytes = placement.as_bytes();
+    if bytes.is_empty() {
+        return false;
+    }
+    let mut i = 0;
+    let mut segment_len = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if b == LOCATION_SEPARATOR as u8 {
+            if segment_len == 0 {
+                return false;
+            }
+            segment_len = 0;
+        } else if b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-' {
+            segment_len += 1;
+        } else {
+            return false;
+        }
+        i += 1;
+    }
+    segment_len != 0
+}
+
+impl FromStr for LocationPath {
+    type Err = plugin_common::anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, LOCATION_SEPARATOR);
+        let (side, window, placement) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(side), Some(window), Some(placement)) => (side, window, placement),
+            _ => bail!("invalid location '{}', expected 'side|window|placement'", s),
+        };
+        let side = Side::ALL
+            .into_iter()
+            .find(|x| x.as_str() == side)
+            .ok_or_else(|| anyhow!("invalid location '{}', unknown side '{}'", s, side))?;
+        let window = Window::ALL
+            .into_iter()
+            .find(|x| x.as_str() == window)
+            .ok_or_else(|| anyhow!("invalid location '{}', unknown window '{}'", s, window))?;
+        Self::new(side, window, placement).map_err(|e| anyhow!("invalid location '{}', {}", s, e))
+    }
+}
+
+impl fmt::Display for LocationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{sep}{}{sep}{}",
+            self.side.as_str(),
+            self.window.as_str(),
+            self.placement,
+            sep = LOCATION_SEPARATOR
+        )
+    }
+}
+
+impl serde::Serialize for LocationPath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for LocationPath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct UiButton {
     pub key: String,
@@ -30,7 +211,7 @@ pub enum UiType {
 
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct Location {
-    pub ui: HashMap<String, UiType>,
+    pub ui: HashMap<LocationPath, UiType>,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -46,6 +227,20 @@ pub struct Config {
     pub peer: Vec<ConfigItem>,
 }
 
+/// The capabilities a plugin needs, declared in `Desc` and shown to users before enabling.
+///
+/// `plugin_base` refuses the messages whose target is not declared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Permission {
+    SendToPeer,
+    WriteSharedConfig,
+    WritePeerConfig,
+    ShowUi,
+    Native,
+    ExtSupport,
+}
+
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct Desc {
     pub id: String,
@@ -61,18 +256,82 @@ pub struct Desc {
     pub location: Location,
     pub config: Config,
     pub listen_events: Vec<String>,
+    /// The minimum supported host version, inclusive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_host_version: Option<String>,
+    /// The maximum supported host version, inclusive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_host_version: Option<String>,
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+    /// The custom call methods, registered by `Handler::register_methods`.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// The version of the config schema, the shared config is migrated to it at init by the
+    /// steps registered by `Handler::register_migrations`.
+    #[serde(default)]
+    pub config_version: u32,
+    /// The policies of the peer methods by the method, see `policy`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub peer_policies: BTreeMap<String, Policy>,
 }
 
-static mut DESC: Option<Desc> = None;
+impl Desc {
+    #[inline]
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// Whether the plugin declares a supported range of the host version.
+    #[inline]
+    pub fn has_host_version_range(&self) -> bool {
+        self.min_host_version.is_some() || self.max_host_version.is_some()
+    }
 
-pub(crate) fn set_desc(desc: Desc) {
-    unsafe {
-        DESC = Some(desc);
+    /// Check if the host version is in the supported range of this plugin.
+    pub fn check_host_version(&self, host_version: &semver::Version) -> ResultType<()> {
+        if let Some(min) = self.min_host_version.as_ref() {
+            let min = parse_version(min).map_err(|e| anyhow!("invalid min_host_version, {}", e))?;
+            if *host_version < min {
+                bail!(
+                    "plugin {} {} requires RustDesk >= {}, but the host is {}",
+                    self.id,
+                    self.version,
+                    min,
+                    host_version
+                );
+            }
+        }
+        if let Some(max) = self.max_host_version.as_ref() {
+            let max = parse_version(max).map_err(|e| anyhow!("invalid max_host_version, {}", e))?;
+            if *host_version > max {
+                bail!(
+                    "plugin {} {} requires RustDesk <= {}, but the host is {}",
+                    self.id,
+                    self.version,
+                    max,
+                    host_version
+                );
+            }
+        }
+        Ok(())
     }
 }
 
-pub(crate) fn get_desc() -> &'static Option<Desc> {
-    unsafe { &DESC }
+/// The descs of the logical plugins of the shared library, a json array returned by `descs()`.
+///
+/// `desc()` still returns the desc object of the first plugin for the older hosts.
+pub fn descs_to_string(descs: &[Desc]) -> ResultType<String> {
+    Ok(plugin_common::serde_json::to_string(descs)?)
+}
+
+/// Parse the version, a leading 'v' is allowed, e.g. "v0.1.0".
+pub fn parse_version(v: &str) -> ResultType<semver::Version> {
+    let v = v.trim();
+    match semver::Version::parse(v.strip_prefix('v').unwrap_or(v)) {
+        Ok(v) => Ok(v),
+        Err(e) => bail!("'{}' is not a valid semver, {}", v, e),
+    }
 }
 
 #[cfg(test)]
@@ -98,4 +357,57 @@ mod tests {
         });
         println!("ui checkbox: {}", serde_json::to_string(&ui).unwrap());
     }
+
+    #[test]
+    fn test_location_path() {
+        const LOCATION: LocationPath =
+            LocationPath::from_static(Side::Host, Window::Main, "settings|plugin");
+        let location: LocationPath = "host|main|settings|plugin".parse().unwrap();
+        assert_eq!(location, LOCATION);
+        assert_eq!(location.to_string(), "host|main|settings|plugin");
+
+        let mut ui = Location::default();
+        ui.ui.insert(LOCATION, UiType::Button(UiButton::default()));
+        let s = serde_json::to_string(&ui).unwrap();
+        assert!(s.contains("\"host|main|settings|plugin\""));
+        let ui: Location = serde_json::from_str(&s).unwrap();
+        assert!(ui.ui.contains_key(&location));
+
+        for invalid in [
+            "",
+            "host|main",
+            "host|main|",
+            "server|main|settings",
+            "host|window|settings",
+            "host|main|settings||plugin",
+            "host|main|Settings",
+        ] {
+            assert!(invalid.parse::<LocationPath>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_check_host_version() {
+        let mut desc = Desc {
+            min_host_version: Some("1.2.0".to_owned()),
+            ..Default::default()
+        };
+        assert!(desc
+            .check_host_version(&parse_version("1.2.0").unwrap())
+            .is_ok());
+        assert!(desc
+            .check_host_version(&parse_version("v1.3.1").unwrap())
+            .is_ok());
+        assert!(desc
+            .check_host_version(&parse_version("1.1.9").unwrap())
+            .is_err());
+        desc.max_host_version = Some("1.2.5".to_owned());
+        assert!(desc
+            .check_host_version(&parse_version("1.2.5").unwrap())
+            .is_ok());
+        assert!(desc
+            .check_host_version(&parse_version("1.3.0").unwrap())
+            .is_err());
+        assert!(parse_version("test version").is_err());
+    }
 }
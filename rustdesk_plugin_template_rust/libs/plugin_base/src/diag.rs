@@ -0,0 +1,251 @@
+//! The self-diagnostics, returned by the built-in method `get_diagnostics`.
+//!
+//! The report tells whether the plugin is initialized, the versions, the active sessions
+//! with the remote peers, the calls in progress, whether the log callback is bound, the last
+//! errors and whether the plugin is poisoned by a panic.
+//!
+//! `get_diagnostics` is answered before the other checks of `plugin_call`, so it also works
+//! before `init` and after a panic.
+
+use crate::{context::get_context, guard, handler::*};
+use plugin_common::{plog, serde_derive::Serialize, serde_json};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The max number of the errors kept for the report, the oldest ones are dropped.
+pub const MAX_ERRORS: usize = 32;
+/// The max length of the kept error message in bytes, the message may embed the args of the peer.
+pub const MAX_ERROR_MESSAGE_LEN: usize = 512;
+
+static PENDING_CALLS: AtomicUsize = AtomicUsize::new(0);
+static ERRORS: Mutex<VecDeque<ErrorRecord>> = Mutex::new(VecDeque::new());
+// The number of the connections by the remote peer id and the side.
+static SESSIONS: Mutex<BTreeMap<(String, &'static str), usize>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorRecord {
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    /// The method of the call, or the entry point for the panics.
+    pub method: String,
+    pub peer: String,
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PluginDiag {
+    pub id: String,
+    pub version: String,
+    pub methods: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub peer: String,
+    /// "client" or "server".
+    pub side: &'static str,
+    pub connections: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostics {
+    /// The init data, the handlers and the descs are set together by `init`.
+    pub initialized: bool,
+    pub host_version: Option<String>,
+    pub json_errors: bool,
+    pub plugins: Vec<PluginDiag>,
+    pub sessions: Vec<Session>,
+    /// The calls in progress, excluding `get_diagnostics`.
+    pub pending_calls: usize,
+    pub log_bound: bool,
+    pub poisoned: bool,
+    /// The last errors, the oldest first.
+    pub last_errors: Vec<ErrorRecord>,
+}
+
+/// The guard of a call in progress.
+pub(crate) struct PendingCall;
+
+impl PendingCall {
+    pub(crate) fn new() -> Self {
+        PENDING_CALLS.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        PENDING_CALLS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(crate) fn record_error(method: &str, peer: &str, code: i32, message: &str) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    let mut errors = ERRORS.lock().unwrap_or_else(PoisonError::into_inner);
+    if errors.len() >= MAX_ERRORS {
+        errors.pop_front();
+    }
+    errors.push_back(ErrorRecord {
+        time,
+        method: method.to_owned(),
+        peer: peer.to_owned(),
+        code,
+        message: truncate(message, MAX_ERROR_MESSAGE_LEN),
+    });
+}
+
+/// Truncate to at most `max_len` bytes at a char boundary, "..." is appended if truncated.
+fn truncate(s: &str, max_len: usize) -> String {
+    if s.len() <= max_len {
+        return s.to_owned();
+    }
+    let mut end = max_len.saturating_sub(3);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &s[..end])
+}
+
+/// Track the sessions by the connection events.
+pub(crate) fn on_listen_event(peer: &str, event: &str) {
+    let (side, open) = match event {
+        EVENT_ON_CONN_CLIENT => ("client", true),
+        EVENT_ON_CONN_SERVER => ("server", true),
+        EVENT_ON_CONN_CLOSE_CLIENT => ("client", false),
+        EVENT_ON_CONN_CLOSE_SERVER => ("server", false),
+        _ => return,
+    };
+    let mut sessions = SESSIONS.lock().unwrap_or_else(PoisonError::into_inner);
+    let key = (peer.to_owned(), side);
+    if open {
+        *sessions.entry(key).or_default() += 1;
+    } else if let Some(n) = sessions.get_mut(&key) {
+        *n -= 1;
+        if *n == 0 {
+            sessions.remove(&key);
+        }
+    }
+}
+
+pub(crate) fn clear() {
+    ERRORS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+    SESSIONS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clear();
+}
+
+/// The report of the current state.
+pub fn get_diagnostics() -> Diagnostics {
+    let ctx = get_context();
+    Diagnostics {
+        initialized: ctx.is_some(),
+        host_version: ctx
+            .as_ref()
+            .and_then(|c| c.host_version())
+            .map(|v| v.to_string()),
+        json_errors: ctx.as_ref().is_some_and(|c| c.host_features().json_errors),
+        plugins: ctx
+            .as_ref()
+            .map(|c| {
+                c.plugins()
+                    .iter()
+                    .map(|p| PluginDiag {
+                        id: p.id().to_owned(),
+                        version: p.desc().version.clone(),
+                        methods: p.desc().methods.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        sessions: SESSIONS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|((peer, side), n)| Session {
+                peer: peer.clone(),
+                side,
+                connections: *n,
+            })
+            .collect(),
+        pending_calls: PENDING_CALLS.load(Ordering::SeqCst),
+        log_bound: plog::__get_log().is_some(),
+        poisoned: guard::is_poisoned(),
+        last_errors: ERRORS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned()
+            .collect(),
+    }
+}
+
+impl Diagnostics {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        on_listen_event("diag peer", EVENT_ON_CONN_SERVER);
+        on_listen_event("diag peer", EVENT_ON_CONN_SERVER);
+        on_listen_event("diag peer", EVENT_ON_CONN_CLIENT);
+        on_listen_event("diag peer", EVENT_ON_CONN_CLOSE_SERVER);
+        on_listen_event("diag peer", EVENT_ON_CONN_CLOSE_CLIENT);
+        on_listen_event("diag peer", EVENT_ON_CONN_CLOSE_CLIENT);
+        let sessions = get_diagnostics()
+            .sessions
+            .into_iter()
+            .filter(|s| s.peer == "diag peer")
+            .map(|s| (s.side, s.connections))
+            .collect::<Vec<_>>();
+        assert_eq!(sessions, [("server", 1)]);
+    }
+
+    #[test]
+    fn test_errors() {
+        for i in 0..MAX_ERRORS + 1 {
+            record_error("diag_method", "", i as _, "failed");
+        }
+        let errors = get_diagnostics().last_errors;
+        assert!(errors.len() <= MAX_ERRORS);
+        let codes = errors
+            .iter()
+            .filter(|e| e.method == "diag_method")
+            .map(|e| e.code)
+            .collect::<Vec<_>>();
+        assert!(!codes.contains(&0));
+        assert!(codes.contains(&(MAX_ERRORS as i32)));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("failed", 6), "failed");
+        assert_eq!(truncate("failed here", 9), "failed...");
+        // Not cut in the middle of a char.
+        assert_eq!(truncate("ab\u{e9}cdef", 6), "ab...");
+        let message = "x".repeat(MAX_ERROR_MESSAGE_LEN * 2);
+        assert_eq!(
+            truncate(&message, MAX_ERROR_MESSAGE_LEN).len(),
+            MAX_ERROR_MESSAGE_LEN
+        );
+    }
+}
@@ -13,6 +13,7 @@ pub const ERR_PLUGIN_LOAD: i32 = 10001;
 pub const ERR_PLUGIN_MSG_INIT: i32 = 10101;
 pub const ERR_PLUGIN_MSG_INIT_INVALID: i32 = 10102;
 pub const ERR_PLUGIN_MSG_GET_LOCAL_PEER_ID: i32 = 10103;
+pub const ERR_PLUGIN_MSG_INIT_FAILED: i32 = 10104;
 // invalid
 pub const ERR_CALL_UNIMPLEMENTED: i32 = 10201;
 pub const ERR_CALL_INVALID_METHOD: i32 = 10202;
@@ -24,6 +25,11 @@ pub const ERR_PEER_ID_MISMATCH: i32 = 10302;
 pub const ERR_CALL_CONFIG_VALUE: i32 = 10303;
 // no handlers on calling
 pub const ERR_NOT_HANDLED: i32 = 10401;
+// panicked
+pub const ERR_PLUGIN_PANIC: i32 = 10501;
+pub const ERR_PLUGIN_POISONED: i32 = 10502;
+// refused by the policy of the peer method
+pub const ERR_PEER_PERMISSION_DENIED: i32 = 10601;
 
 // ======================================================
 // Errors from RustDesk callbacks.
@@ -43,6 +49,21 @@ pub const ERR_CALLBACK_FAILED: i32 = 21001;
 
 pub const ERR_PLUGIN_HANDLE_BASE: i32 = 30000;
 
-pub const EER_CALL_FAILED: i32 = 30021;
+pub const ERR_CALL_FAILED: i32 = 30021;
+#[deprecated(note = "use ERR_CALL_FAILED")]
+pub const EER_CALL_FAILED: i32 = ERR_CALL_FAILED;
+// the target of the call is not declared in the permissions of the desc
+pub const ERR_PERMISSION_NOT_DECLARED: i32 = 30031;
+// the local storage failed to read or write
+pub const ERR_STORAGE_IO: i32 = 30041;
+// the local storage is full
+pub const ERR_STORAGE_QUOTA: i32 = 30042;
+// the remote peer failed to turn the option on or off
 pub const ERR_PEER_ON_FAILED: i32 = 40012;
-pub const ERR_PEER_OFF_FAILED: i32 = 40012;
+pub const ERR_PEER_OFF_FAILED: i32 = 40013;
+
+// ======================================================
+// Errors defined by the plugins, `PluginError::Custom`.
+
+pub const ERR_PLUGIN_CUSTOM_BASE: i32 = 50000;
+pub const ERR_PLUGIN_CUSTOM_MAX: i32 = 59999;
@@ -0,0 +1,383 @@
+//! The structured errors of the plugin, mapped to the stable codes in `errno`.
+//!
+//! Handlers can return `HandlerResult` and use `?`, the error is turned into
+//! `HandlerRet` or `PluginReturn` with its code and message.
+//!
+//! If the host announces `HostFeatures::json_errors`, `PluginReturn.msg` of the errors
+//! is `ErrorDetails` in JSON instead of the plain text.
+
+use crate::{context::get_context, errno::*, handler::HandlerRet, PluginReturn};
+use plugin_common::{anyhow, serde_derive::Serialize, serde_json};
+use std::fmt;
+
+pub type HandlerResult = Result<HandlerRet, PluginError>;
+
+macro_rules! plugin_errors {
+    ($($(#[$meta:meta])* $name:ident = $code:ident, $text:literal;)+) => {
+        /// The error of the plugin, each variant has a stable code in `errno`.
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum PluginError {
+            $($(#[$meta])* $name(String),)+
+            /// The errors defined by the plugin, created by `PluginError::custom`.
+            Custom(CustomCode, String),
+        }
+
+        impl PluginError {
+            pub fn code(&self) -> i32 {
+                match self {
+                    $(Self::$name(_) => $code,)+
+                    Self::Custom(code, _) => code.get(),
+                }
+            }
+
+            /// The detail message.
+            pub fn msg(&self) -> &str {
+                match self {
+                    $(Self::$name(msg))|+ | Self::Custom(_, msg) => msg,
+                }
+            }
+
+            /// The user-facing text of the error, without the detail.
+            pub fn text(&self) -> &'static str {
+                match self {
+                    $(Self::$name(_) => $text,)+
+                    Self::Custom(..) => "Plugin error",
+                }
+            }
+
+            /// Map the code returned by the plugin or the host back to the error.
+            ///
+            /// `None` if the code is not an error or is unknown.
+            pub fn from_code(code: i32, msg: String) -> Option<Self> {
+                match code {
+                    $($code => Some(Self::$name(msg)),)+
+                    ERR_PLUGIN_CUSTOM_BASE..=ERR_PLUGIN_CUSTOM_MAX => {
+                        Some(Self::Custom(CustomCode(code), msg))
+                    }
+                    _ => None,
+                }
+            }
+        }
+
+        const _: () = assert!(
+            is_unique(&[$($code,)+]),
+            "The error codes must be unique"
+        );
+        const _: () = assert!(
+            is_below(&[$($code,)+], ERR_PLUGIN_CUSTOM_BASE),
+            "The custom error range is reserved for the plugins"
+        );
+    };
+}
+
+plugin_errors! {
+    // Errors from the plugins, must be handled by RustDesk.
+    Load = ERR_PLUGIN_LOAD, "Plugin is not loaded";
+    NotInitialized = ERR_PLUGIN_MSG_INIT, "Plugin is not initialized";
+    InitInvalid = ERR_PLUGIN_MSG_INIT_INVALID, "Invalid init data";
+    GetLocalPeerId = ERR_PLUGIN_MSG_GET_LOCAL_PEER_ID, "Failed to get the local peer id";
+    InitFailed = ERR_PLUGIN_MSG_INIT_FAILED, "Failed to initialize the plugin";
+    Unimplemented = ERR_CALL_UNIMPLEMENTED, "Not implemented";
+    InvalidMethod = ERR_CALL_INVALID_METHOD, "Invalid method";
+    NotSupportedMethod = ERR_CALL_NOT_SUPPORTED_METHOD, "Unsupported method";
+    InvalidPeer = ERR_CALL_INVALID_PEER, "Invalid peer id";
+    InvalidArgs = ERR_CALL_INVALID_ARGS, "Invalid arguments";
+    PeerIdMismatch = ERR_PEER_ID_MISMATCH, "Plugin id mismatch";
+    ConfigValue = ERR_CALL_CONFIG_VALUE, "Invalid config value";
+    NotHandled = ERR_NOT_HANDLED, "Not handled";
+    Panic = ERR_PLUGIN_PANIC, "Plugin panicked";
+    Poisoned = ERR_PLUGIN_POISONED, "Plugin is poisoned";
+    PeerPermissionDenied = ERR_PEER_PERMISSION_DENIED, "Permission denied";
+    // Errors from RustDesk callbacks.
+    CallbackPluginId = ERR_CALLBACK_PLUGIN_ID, "Unknown plugin id";
+    CallbackInvalidArgs = ERR_CALLBACK_INVALID_ARGS, "Invalid callback arguments";
+    CallbackInvalidMsg = ERR_CALLBACK_INVALID_MSG, "Invalid callback message";
+    CallbackTarget = ERR_CALLBACK_TARGET, "Invalid callback target";
+    CallbackTargetType = ERR_CALLBACK_TARGET_TYPE, "Invalid callback target type";
+    CallbackPeerNotFound = ERR_CALLBACK_PEER_NOT_FOUND, "Peer not found";
+    CallbackFailed = ERR_CALLBACK_FAILED, "Callback failed";
+    // Errors from the plugins, should be handled by the plugins.
+    CallFailed = ERR_CALL_FAILED, "Call failed";
+    PermissionNotDeclared = ERR_PERMISSION_NOT_DECLARED, "Permission is not declared";
+    StorageIo = ERR_STORAGE_IO, "Storage failed";
+    StorageQuota = ERR_STORAGE_QUOTA, "Storage quota exceeded";
+    PeerOnFailed = ERR_PEER_ON_FAILED, "Failed to turn on";
+    PeerOffFailed = ERR_PEER_OFF_FAILED, "Failed to turn off";
+}
+
+const fn is_unique(codes: &[i32]) -> bool {
+    let mut i = 0;
+    while i < codes.len() {
+        let mut j = i + 1;
+        while j < codes.len() {
+            if codes[i] == codes[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+const fn is_below(codes: &[i32], max: i32) -> bool {
+    let mut i = 0;
+    while i < codes.len() {
+        if codes[i] >= max {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// The code of `PluginError::Custom`, always in
+/// `ERR_PLUGIN_CUSTOM_BASE..=ERR_PLUGIN_CUSTOM_MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CustomCode(i32);
+
+impl CustomCode {
+    /// `None` if the code is out of the custom range.
+    ///
+    /// It is const, the codes of the plugin can be checked at compile time, e.g.
+    /// `const _: () = assert!(CustomCode::new(MY_CODE).is_some());`.
+    pub const fn new(code: i32) -> Option<Self> {
+        if code >= ERR_PLUGIN_CUSTOM_BASE && code <= ERR_PLUGIN_CUSTOM_MAX {
+            Some(Self(code))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub const fn get(self) -> i32 {
+        self.0
+    }
+}
+
+impl PluginError {
+    /// The error defined by the plugin.
+    ///
+    /// code: In `ERR_PLUGIN_CUSTOM_BASE..=ERR_PLUGIN_CUSTOM_MAX`, the codes out of the range
+    /// are reported as `CallFailed`.
+    pub fn custom(code: i32, msg: impl Into<String>) -> Self {
+        match CustomCode::new(code) {
+            Some(code) => Self::Custom(code, msg.into()),
+            None => Self::CallFailed(format!(
+                "custom error code {} out of range, {}",
+                code,
+                msg.into()
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.msg().is_empty() {
+            write!(f, "{}", self.text())
+        } else {
+            write!(f, "{}, {}", self.text(), self.msg())
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+impl From<anyhow::Error> for PluginError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<PluginError>() {
+            Ok(e) => e,
+            Err(e) => Self::CallFailed(format!("{:#}", e)),
+        }
+    }
+}
+
+impl From<serde_json::Error> for PluginError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::InvalidArgs(e.to_string())
+    }
+}
+
+impl From<std::str::Utf8Error> for PluginError {
+    fn from(e: std::str::Utf8Error) -> Self {
+        Self::InvalidArgs(e.to_string())
+    }
+}
+
+impl From<PluginError> for HandlerRet {
+    fn from(e: PluginError) -> Self {
+        Self::new(e.code(), e.to_string()).with_details((&e).into())
+    }
+}
+
+impl From<HandlerResult> for HandlerRet {
+    fn from(r: HandlerResult) -> Self {
+        r.unwrap_or_else(Into::into)
+    }
+}
+
+impl From<PluginError> for PluginReturn {
+    fn from(e: PluginError) -> Self {
+        error_return(e.code(), &e.to_string(), Some(&(&e).into()))
+    }
+}
+
+/// The structured error sent to the host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorDetails {
+    pub code: i32,
+    pub message: String,
+    /// The causes, from the outermost to the innermost.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub context: Vec<String>,
+    /// The same call may succeed later.
+    pub retryable: bool,
+    /// The localized message to show to the user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_message: Option<String>,
+}
+
+impl ErrorDetails {
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            context: Vec::new(),
+            retryable: is_retryable(code),
+            user_message: None,
+        }
+    }
+
+    /// The message is the outermost error, the context is the chain of the causes.
+    pub fn from_anyhow(code: i32, e: &anyhow::Error) -> Self {
+        let mut chain = e.chain().map(|c| c.to_string());
+        let mut details = Self::new(code, chain.next().unwrap_or_default());
+        details.context = chain.collect();
+        details
+    }
+
+    pub fn context(mut self, context: impl Into<String>) -> Self {
+        self.context.push(context.into());
+        self
+    }
+
+    pub fn retryable(mut self, retryable: bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    pub fn user_message(mut self, user_message: impl Into<String>) -> Self {
+        self.user_message = Some(user_message.into());
+        self
+    }
+}
+
+impl From<&PluginError> for ErrorDetails {
+    fn from(e: &PluginError) -> Self {
+        let details = Self::new(e.code(), e.text());
+        if e.msg().is_empty() {
+            details
+        } else {
+            details.context(e.msg())
+        }
+    }
+}
+
+/// The errors caused by the state of the host or the peer, not by the call.
+fn is_retryable(code: i32) -> bool {
+    matches!(
+        code,
+        ERR_PLUGIN_MSG_INIT
+            | ERR_PLUGIN_MSG_GET_LOCAL_PEER_ID
+            | ERR_CALLBACK_PEER_NOT_FOUND
+            | ERR_CALLBACK_FAILED
+    )
+}
+
+/// The error return, `msg` is replaced by the details in JSON if the host supports it.
+pub fn error_return(code: i32, msg: &str, details: Option<&ErrorDetails>) -> PluginReturn {
+    let json_errors = get_context()
+        .map(|c| c.host_features().json_errors)
+        .unwrap_or(false);
+    if !json_errors {
+        return PluginReturn::new(code, msg);
+    }
+    let mut details = details
+        .cloned()
+        .unwrap_or_else(|| ErrorDetails::new(code, msg));
+    details.code = code;
+    match serde_json::to_string(&details) {
+        Ok(json) => PluginReturn::new(code, &json),
+        Err(_) => PluginReturn::new(code, msg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plugin_error() {
+        let e = PluginError::InvalidArgs("missing id".to_owned());
+        assert_eq!(e.code(), ERR_CALL_INVALID_ARGS);
+        assert_eq!(e.to_string(), "Invalid arguments, missing id");
+        assert_eq!(
+            PluginError::from_code(e.code(), e.msg().to_owned()),
+            Some(e.clone())
+        );
+        assert_eq!(
+            PluginError::from_code(ERR_PLUGIN_CUSTOM_BASE + 1, "".to_owned()),
+            Some(PluginError::custom(ERR_PLUGIN_CUSTOM_BASE + 1, ""))
+        );
+        assert_eq!(
+            PluginError::from_code(ERR_PEER_OFF_FAILED, "".to_owned()).map(|e| e.code()),
+            Some(ERR_PEER_OFF_FAILED)
+        );
+        assert_eq!(CustomCode::new(ERR_PEER_OFF_FAILED), None);
+        const _: () = assert!(CustomCode::new(ERR_PLUGIN_CUSTOM_MAX).is_some());
+        assert_eq!(
+            PluginError::custom(ERR_PEER_ON_FAILED, "").code(),
+            ERR_CALL_FAILED
+        );
+        assert_eq!(PluginError::from_code(ERR_SUCCESS, "".to_owned()), None);
+
+        let e: PluginError = anyhow::anyhow!(e).into();
+        assert_eq!(e.code(), ERR_CALL_INVALID_ARGS);
+        let e: PluginError = serde_json::from_str::<String>("{").unwrap_err().into();
+        assert_eq!(e.code(), ERR_CALL_INVALID_ARGS);
+
+        let parse = |s: &str| -> HandlerResult {
+            let v: u32 = serde_json::from_str(s)?;
+            Ok(HandlerRet::new(ERR_SUCCESS, v.to_string()))
+        };
+        assert_eq!(HandlerRet::from(parse("1")).code, ERR_SUCCESS);
+        assert_eq!(HandlerRet::from(parse("x")).code, ERR_CALL_INVALID_ARGS);
+    }
+
+    #[test]
+    fn test_error_details() {
+        let e = anyhow::anyhow!("connection refused").context("send message to peer");
+        let details = ErrorDetails::from_anyhow(ERR_CALLBACK_FAILED, &e).user_message("重试");
+        assert_eq!(
+            serde_json::to_value(&details).unwrap(),
+            serde_json::json!({
+                "code": ERR_CALLBACK_FAILED,
+                "message": "send message to peer",
+                "context": ["connection refused"],
+                "retryable": true,
+                "user_message": "重试",
+            })
+        );
+
+        let details = ErrorDetails::from(&PluginError::InvalidArgs("".to_owned()));
+        assert_eq!(
+            serde_json::to_value(&details).unwrap(),
+            serde_json::json!({
+                "code": ERR_CALL_INVALID_ARGS,
+                "message": "Invalid arguments",
+                "retryable": false,
+            })
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Panic containment at the C ABI entry points.
+//!
+//! A panic must not unwind across the FFI boundary. Every exported function of the plugin
+//! should be wrapped by `catch_panic` or `catch_panic_or`. The panic is caught, logged through
+//! the host log callback with its location, and turned into `errno::ERR_PLUGIN_PANIC`.
+//!
+//! The recovery runs outside `catch_unwind`, so the locks it takes (the context, the diagnostics
+//! and the log) ignore the poisoning left by a previous panic.
+
+use crate::{errno::*, error::error_return, PluginReturn};
+use std::{
+    any::Any,
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Once,
+    },
+};
+
+/// What to do with the plugin after a panic is caught.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    /// The plugin stays usable, only the panicked call fails.
+    Continue = 0,
+    /// The plugin is marked poisoned, the later calls fail with `ERR_PLUGIN_POISONED`
+    /// until the plugin is cleared.
+    Poison = 1,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Continue as u8);
+static POISONED: AtomicBool = AtomicBool::new(false);
+static HOOK: Once = Once::new();
+
+thread_local! {
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn set_panic_policy(policy: PanicPolicy) {
+    POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+pub fn get_panic_policy() -> PanicPolicy {
+    match POLICY.load(Ordering::SeqCst) {
+        1 => PanicPolicy::Poison,
+        _ => PanicPolicy::Continue,
+    }
+}
+
+#[inline]
+pub fn is_poisoned() -> bool {
+    POISONED.load(Ordering::SeqCst)
+}
+
+pub(crate) fn clear_poisoned() {
+    POISONED.store(false, Ordering::SeqCst);
+}
+
+/// The error to return if the plugin is poisoned.
+pub(crate) fn poisoned_return() -> Option<PluginReturn> {
+    if is_poisoned() {
+        Some(error_return(
+            ERR_PLUGIN_POISONED,
+            "Plugin is poisoned by a previous panic, it must be cleared and initialized again",
+            None,
+        ))
+    } else {
+        None
+    }
+}
+
+/// Run the entry point, a panic is caught and turned into `ERR_PLUGIN_PANIC`.
+///
+/// entry: The name of the entry point, for logging.
+pub fn catch_panic<F: FnOnce() -> PluginReturn>(entry: &str, f: F) -> PluginReturn {
+    match run(entry, f) {
+        Ok(ret) => ret,
+        Err(msg) => error_return(ERR_PLUGIN_PANIC, &msg, None),
+    }
+}
+
+/// Run the entry point, `default` is returned if a panic is caught.
+pub fn catch_panic_or<T, F: FnOnce() -> T>(entry: &str, default: T, f: F) -> T {
+    run(entry, f).unwrap_or(default)
+}
+
+fn run<T, F: FnOnce() -> T>(entry: &str, f: F) -> Result<T, String> {
+    install_hook();
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(v) => Ok(v),
+        Err(payload) => {
+            let location = PANIC_LOCATION
+                .with(|l| l.borrow_mut().take())
+                .unwrap_or_else(|| "unknown location".to_owned());
+            let msg = format!(
+                "Panic in '{}' at {}: {}",
+                entry,
+                location,
+                payload_to_string(payload.as_ref())
+            );
+            plugin_common::error!("{}", &msg);
+            crate::diag::record_error(entry, "", ERR_PLUGIN_PANIC, &msg);
+            if get_panic_policy() == PanicPolicy::Poison {
+                POISONED.store(true, Ordering::SeqCst);
+            }
+            Err(msg)
+        }
+    }
+}
+
+fn install_hook() {
+    HOOK.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if let Some(location) = info.location() {
+                let location = format!(
+                    "{}:{}:{}",
+                    location.file(),
+                    location.line(),
+                    location.column()
+                );
+                PANIC_LOCATION.with(|l| *l.borrow_mut() = Some(location));
+            }
+            prev(info);
+        }));
+    });
+}
+
+fn payload_to_string(payload: &(dyn Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_owned()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch_panic() {
+        let mut ret = catch_panic("test", || panic!("test panic"));
+        let (code, msg) = ret.get_code_msg();
+        assert_eq!(code, ERR_PLUGIN_PANIC);
+        assert!(msg.contains("test panic"), "{}", msg);
+        assert!(msg.contains(file!()), "{}", msg);
+        assert!(!is_poisoned());
+
+        assert!(catch_panic_or("test", std::ptr::null::<u8>(), || panic!()).is_null());
+        assert!(catch_panic("test", PluginReturn::success).is_success());
+    }
+}
@@ -1,9 +1,22 @@
-use crate::{desc::Desc, errno::*};
+use crate::{
+    args_to_string,
+    context::{get_context, update_context},
+    cstr_to_string,
+    desc::{Desc, LocationPath},
+    errno::*,
+    error::ErrorDetails,
+    method::Methods,
+    migration::Migrations,
+    policy::Policies,
+};
 use plugin_common::{
     serde_derive::{Deserialize, Serialize},
     serde_json, ResultType,
 };
-use std::ffi::{c_char, c_void, CStr};
+use std::{
+    ffi::{c_char, c_void},
+    sync::Arc,
+};
 
 pub const MSG_TO_UI_FLUTTER_CHANNEL_MAIN: u16 = 0x01 << 0;
 #[cfg(not(any(target_os = "android", target_os = "ios")))]
@@ -17,6 +30,12 @@ pub const MSG_TO_UI_FLUTTER_CHANNEL_FORWARD: u16 = 0x01 << 4;
 pub const METHOD_HANDLE_UI: &[u8; 10] = b"handle_ui\0";
 pub const METHOD_HANDLE_PEER: &[u8; 12] = b"handle_peer\0";
 pub const METHOD_HANDLE_LISTEN_EVENT: &[u8; 20] = b"handle_listen_event\0";
+/// Built-in, the host announces its optional features, args: `HostFeatures` in JSON.
+pub const METHOD_SET_HOST_FEATURES: &[u8; 18] = b"set_host_features\0";
+/// Built-in, the metrics of the calls, args: `stats::MsgGetStats` in JSON or empty.
+pub const METHOD_GET_STATS: &[u8; 10] = b"get_stats\0";
+/// Built-in, the report of `diag::Diagnostics` in JSON, args: ignored.
+pub const METHOD_GET_DIAGNOSTICS: &[u8; 16] = b"get_diagnostics\0";
 pub const EVENT_ON_CONN_CLIENT: &str = "on_conn_client";
 pub const EVENT_ON_CONN_SERVER: &str = "on_conn_server";
 pub const EVENT_ON_CONN_CLOSE_CLIENT: &str = "on_conn_close_client";
@@ -33,11 +52,11 @@ macro_rules! early_return_value {
     ($e:expr, $code: ident, $($arg:tt)*) => {
         match $e {
             Err(e) => {
-                return $crate::handler::HandlerRet {
-                    code: $code,
-                    msg: format!("Failed to {} '{:?}'", format_args!($($arg)*), e),
-                    msgs: $crate::handler::Msgs::default(),
-                };
+                let action = format!("Failed to {}", format_args!($($arg)*));
+                return $crate::handler::HandlerRet::new($code, format!("{} '{:?}'", action, e))
+                    .with_details(
+                        $crate::error::ErrorDetails::new($code, action).context(e.to_string()),
+                    );
             }
             Ok(v) => v,
         }
@@ -48,11 +67,7 @@ macro_rules! early_return_value {
 macro_rules! early_return_if_true {
     ($e:expr, $code: ident, $($arg:tt)*) => {
         if $e {
-            return $crate::handler::HandlerRet {
-                code: $code,
-                msg: format_args!($($arg)*).to_string(),
-                msgs: $crate::handler::Msgs::default(),
-            };
+            return $crate::handler::HandlerRet::new($code, format_args!($($arg)*).to_string());
         }
     };
 }
@@ -85,7 +100,7 @@ impl MsgToUi {
 #[derive(Serialize)]
 pub struct ConfigToUi {
     pub channel: u16,
-    pub location: String,
+    pub location: LocationPath,
 }
 
 #[derive(Serialize)]
@@ -134,12 +149,60 @@ pub struct Msgs {
 pub struct MsgFromUi {
     pub id: String,
     pub name: String,
-    pub location: String,
+    pub location: LocationPath,
     pub key: String,
     pub value: String,
     pub action: String,
 }
 
+/// The message between the plugins of the peers, the id is used to dispatch the message.
+#[derive(Serialize, Deserialize)]
+pub struct MsgPeer {
+    pub id: String,
+    pub name: String,
+    pub method: String,
+    pub content: String,
+}
+
+impl MsgPeer {
+    #[inline]
+    pub fn new_string(d: &Desc, method: String, content: String) -> String {
+        let mut s = serde_json::to_string(&MsgPeer {
+            id: d.id.clone(),
+            name: d.name.clone(),
+            method,
+            content,
+        })
+        .unwrap();
+        // Add trailing 0 to make it a C string in this case
+        s.push('\0');
+        s
+    }
+
+    #[inline]
+    pub fn fill_out(
+        d: &Desc,
+        method: String,
+        content: String,
+        out: *mut *mut c_void,
+        out_len: *mut usize,
+    ) {
+        let s = Self::new_string(d, method, content);
+        crate::mem::bytes_to_out(s.as_bytes(), out, out_len);
+    }
+
+    #[inline]
+    pub fn from_c_str(msg: *const c_char) -> ResultType<Self> {
+        Ok(serde_json::from_str(&cstr_to_string(msg)?)?)
+    }
+
+    /// Parse the args of `len` bytes.
+    #[inline]
+    pub fn from_args(args: *const c_void, len: usize) -> ResultType<Self> {
+        Ok(serde_json::from_str(&args_to_string(args, len)?)?)
+    }
+}
+
 #[derive(Deserialize)]
 pub struct MsgListenEvent {
     pub event: String,
@@ -148,31 +211,72 @@ pub struct MsgListenEvent {
 impl MsgListenEvent {
     #[inline]
     pub fn from_cstr(cstr: *const c_char) -> ResultType<Self> {
-        Ok(serde_json::from_str(unsafe {
-            CStr::from_ptr(cstr).to_str()?
-        })?)
+        Ok(serde_json::from_str(&cstr_to_string(cstr)?)?)
+    }
+
+    /// Parse the args of `len` bytes.
+    #[inline]
+    pub fn from_args(args: *const c_void, len: usize) -> ResultType<Self> {
+        Ok(serde_json::from_str(&args_to_string(args, len)?)?)
     }
 }
 
+/// The result of a handler.
+///
+/// Struct literals should end with `..Default::default()`, or use the constructors
+/// `HandlerRet::new`, `HandlerRet::success` and `HandlerRet::error`.
 pub struct HandlerRet {
     pub code: i32,
     pub msg: String,
     pub msgs: Msgs,
+    /// The structured error, sent instead of `msg` if the host supports JSON errors.
+    pub details: Option<Box<ErrorDetails>>,
 }
 
 impl Default for HandlerRet {
     fn default() -> Self {
         Self {
             code: ERR_CALL_INVALID_ARGS,
-            msg: format!("Default return msg"),
+            msg: "Default return msg".to_owned(),
             msgs: Msgs::default(),
+            details: None,
         }
     }
 }
 
-static mut PLUGIN_HANDLER: Option<Box<dyn Handler>> = None;
+impl HandlerRet {
+    pub fn new(code: i32, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+            msgs: Msgs::default(),
+            details: None,
+        }
+    }
+
+    #[inline]
+    pub fn success() -> Self {
+        Self::new(ERR_SUCCESS, "")
+    }
+
+    /// Set the structured error, sent instead of `msg` if the host supports JSON errors.
+    pub fn with_details(mut self, details: ErrorDetails) -> Self {
+        self.details = Some(Box::new(details));
+        self
+    }
+
+    /// The error return with the structured details.
+    pub fn error(details: ErrorDetails) -> Self {
+        Self {
+            code: details.code,
+            msg: details.message.clone(),
+            msgs: Msgs::default(),
+            details: Some(Box::new(details)),
+        }
+    }
+}
 
-pub trait Handler {
+pub trait Handler: Send + Sync {
     fn handle_ui_event(&self, d: &Desc, local_peer_id: String, msg_ui: MsgFromUi) -> HandlerRet;
     fn handle_client_event(
         &self,
@@ -190,14 +294,55 @@ pub trait Handler {
         remote_peer_id: &str,
         event: MsgListenEvent,
     ) -> HandlerRet;
-}
 
-pub fn set_handler(handler: Box<dyn Handler>) {
-    unsafe {
-        PLUGIN_HANDLER = Some(handler);
+    /// Called after the plugin is initialized, the context is already set.
+    ///
+    /// The init fails and the plugin is cleared if an error is returned.
+    fn on_init(&self, _d: &Desc) -> ResultType<()> {
+        Ok(())
     }
+
+    /// Called after the init data is replaced by `reset`.
+    fn on_reset(&self, _d: &Desc) {}
+
+    /// Called before the plugin is cleared, stop the threads and flush the state here.
+    fn on_clear(&self, _d: &Desc) {}
+
+    /// Register the custom call methods declared in `Desc::methods`.
+    fn register_methods(&self, _methods: &mut Methods) {}
+
+    /// Register the config migrations up to `Desc::config_version`.
+    fn register_migrations(&self, _migrations: &mut Migrations) {}
+
+    /// Register the policies of the peer methods, checked before `handle_client_event` and
+    /// `handle_server_event`.
+    ///
+    /// Added to the ones declared in `Desc::peer_policies`, prefer declaring them in the desc
+    /// so the host can show them.
+    fn register_policies(&self, _policies: &mut Policies) {}
+}
+
+/// The handler of the plugin, the first one if the library has several plugins.
+///
+/// The handler is shared with the context, it is no longer `&'static Option<Box<dyn Handler>>`.
+#[deprecated(note = "use `context::get_context()` and `Plugin::handler`")]
+pub fn get_handler() -> Option<Arc<dyn Handler>> {
+    get_context()?.plugins().first().map(|p| p.shared_handler())
 }
 
-pub fn get_handler() -> &'static Option<Box<dyn Handler>> {
-    unsafe { &PLUGIN_HANDLER }
+/// Replace the handler of the plugin, the first one if the library has several plugins.
+///
+/// Nothing is done if the plugin is not initialized, or the new handler doesn't match the desc.
+#[deprecated(note = "pass the handler to `init::init` or `init::init_plugins`")]
+pub fn set_handler(handler: Box<dyn Handler>) {
+    let Some(plugin) = get_context().and_then(|c| c.plugins().first().cloned()) else {
+        return;
+    };
+    // Registered out of the lock, the handler may read the context.
+    let plugin = plugin.with_handler(handler);
+    if let Err(e) = crate::init::check_plugin(&plugin) {
+        plugin_common::error!("Failed to set the handler, {}", e);
+        return;
+    }
+    update_context(|c| c.with_plugin(plugin));
 }
@@ -1,9 +1,31 @@
-use crate::*;
-use std::ffi::{c_char, c_void};
+use crate::{
+    context::{get_context, set_context, update_context, Context, Plugin},
+    *,
+};
+use plugin_common::{
+    log::LevelFilter,
+    plog,
+    plog_file::{self, FileLog, FileMode},
+    semver::Version,
+    CbLog,
+};
+use std::{
+    ffi::c_char,
+    sync::{Arc, Mutex},
+};
 
-lazy_static! {
-    static ref INIT_DATA: Arc<Mutex<Option<InitData>>> = Default::default();
-}
+/// The shared config of the max log level, "off", "error", "warn", "info", "debug" or "trace".
+pub const CONF_KEY_LOG_LEVEL: &str = "log-level";
+/// The shared config to mirror the log to the local file, "1" or "0".
+///
+/// The file is under `data::log_dir`, it is written only when the host log callback is not set
+/// if the config is not "1".
+pub const CONF_KEY_LOG_TO_FILE: &str = "log-to-file";
+const DEFAULT_LOG_LEVEL: LevelFilter = if cfg!(debug_assertions) {
+    LevelFilter::Trace
+} else {
+    LevelFilter::Info
+};
 
 #[repr(C)]
 pub struct InitData {
@@ -11,74 +33,550 @@ pub struct InitData {
     pub cbs: Callbacks,
 }
 
+// The version is owned and never changed, the callbacks are plain function pointers.
 unsafe impl Send for InitData {}
+unsafe impl Sync for InitData {}
 
 impl Clone for InitData {
     fn clone(&self) -> Self {
-        unsafe {
-            let version = libc::malloc(libc::strlen(self.version) + 1) as *mut c_char;
-            libc::memcpy(
-                version as *mut libc::c_void,
-                self.version as *const libc::c_void,
-                libc::strlen(self.version) + 1,
-            );
-            let cbs = self.cbs;
-            InitData { version, cbs }
+        InitData {
+            version: mem::dup_cstr(self.version),
+            cbs: self.cbs,
         }
     }
 }
 
 impl Drop for InitData {
     fn drop(&mut self) {
-        unsafe {
-            if !self.version.is_null() {
-                libc::free(self.version as *mut c_void);
-            }
-        }
+        mem::free(self.version as _);
     }
 }
 
+/// A copy of the init data, `None` if the plugin is not initialized.
+///
+/// The init data is kept in the context, the changes of the copy are ignored.
+#[deprecated(note = "use `context::get_context()` and `Context::init_data`")]
 pub fn get_init_data() -> Arc<Mutex<Option<InitData>>> {
-    INIT_DATA.clone()
+    Arc::new(Mutex::new(get_context().map(|c| c.init_data().clone())))
+}
+
+/// Check the registries of the handler against the desc.
+pub(crate) fn check_plugin(plugin: &Plugin) -> Result<(), String> {
+    method::check_methods(plugin.desc(), plugin.methods())
+        .and_then(|_| migration::check_migrations(plugin.desc(), plugin.migrations()))
+        .and_then(|_| policy::check_policies(plugin.desc(), plugin.policies()))
+}
+
+/// The parsed version of the host, handlers can use it to gate features.
+///
+/// `None` if not initialized, or the host version is not a semver.
+pub fn get_host_version() -> Option<Version> {
+    get_context()?.host_version().cloned()
 }
 
+/// Init the plugin, the single plugin version of `init_plugins`.
 pub fn init(
     handler: Box<dyn handler::Handler>,
     desc: desc::Desc,
     info: *const InitData,
 ) -> PluginReturn {
-    let ret = set_init_data(info);
-    if !ret.is_success() {
-        return ret;
+    init_plugins(vec![(handler, desc)], info)
+}
+
+/// Init the logical plugins in the shared library.
+///
+/// The plugins are dispatched by the id in the messages, the ids must be unique.
+pub fn init_plugins(
+    plugins: Vec<(Box<dyn handler::Handler>, desc::Desc)>,
+    info: *const InitData,
+) -> PluginReturn {
+    if plugins.is_empty() {
+        return PluginReturn::new(
+            crate::errno::ERR_PLUGIN_MSG_INIT_INVALID,
+            "No plugin to init",
+        );
+    }
+    // Set before the checks, the lines of a failed init are kept while the host log callback
+    // is not bound.
+    init_file_log(&plugins[0].1.id);
+    for (i, (_, desc)) in plugins.iter().enumerate() {
+        if plugins[..i].iter().any(|(_, d)| d.id == desc.id) {
+            return invalid_init_data(&format!("Duplicated plugin id {}", desc.id));
+        }
+    }
+    let descs = plugins.iter().map(|(_, d)| d).collect::<Vec<_>>();
+    let (init_data, host_version) = match load_init_data(&descs, info) {
+        Ok(v) => v,
+        Err(ret) => return ret,
+    };
+    let plugins = plugins
+        .into_iter()
+        .map(|(handler, desc)| Plugin::new(desc, handler))
+        .collect::<Vec<_>>();
+    for plugin in plugins.iter() {
+        if let Err(e) = check_plugin(plugin) {
+            return invalid_init_data(&e);
+        }
+    }
+    // Start clean if the plugin is initialized again without `clear`, the log file is kept.
+    clear_state();
+
+    let log = init_data.cbs.log;
+    set_context(Some(Context::new(init_data, host_version, plugins)));
+    set_log(log);
+
+    if let Some(context) = get_context() {
+        let plugins = context.plugins();
+        for (i, plugin) in plugins.iter().enumerate() {
+            if let Err(e) = migration::migrate(plugin.desc(), plugin.migrations()) {
+                let msg = format!("Failed to migrate the config of {}, {}", plugin.id(), e);
+                return init_failed(&plugins[..i], &msg);
+            }
+            if let Err(e) = plugin.handler().on_init(plugin.desc()) {
+                let msg = format!("Failed to init plugin {}, {}", plugin.id(), e);
+                return init_failed(&plugins[..i], &msg);
+            }
+        }
     }
-    handler::set_handler(handler);
-    desc::set_desc(desc);
-    plugin_common::plog::set_log(INIT_DATA.lock().unwrap().as_ref().unwrap().cbs.log);
     PluginReturn::success()
 }
 
+/// Tear down the state of a failed init, only the plugins whose `on_init` finished are cleared
+/// by their handlers.
+fn init_failed(inited: &[Arc<Plugin>], msg: &str) -> PluginReturn {
+    plugin_common::error!("{}", msg);
+    for plugin in inited {
+        plugin.handler().on_clear(plugin.desc());
+    }
+    reset_state();
+    plog_file::clear_file_log();
+    PluginReturn::new(crate::errno::ERR_PLUGIN_MSG_INIT_FAILED, msg)
+}
+
 pub fn reset(info: *const InitData) -> PluginReturn {
-    let ret = set_init_data(info);
-    if !ret.is_success() {
+    if let Some(ret) = guard::poisoned_return() {
         return ret;
     }
+    let context = match get_context() {
+        Some(context) => context,
+        None => {
+            return PluginReturn::new(
+                crate::errno::ERR_PLUGIN_MSG_INIT,
+                "Plugin must be initialized before reset",
+            )
+        }
+    };
+    let descs = context
+        .plugins()
+        .iter()
+        .map(|p| p.desc())
+        .collect::<Vec<_>>();
+    let (init_data, host_version) = match load_init_data(&descs, info) {
+        Ok(v) => v,
+        Err(ret) => return ret,
+    };
+    let log = init_data.cbs.log;
+    // Derived under the lock, the host features set in between are kept.
+    if !update_context(|c| c.with_init_data(init_data, host_version)) {
+        return PluginReturn::new(
+            crate::errno::ERR_PLUGIN_MSG_INIT,
+            "Plugin is cleared during reset",
+        );
+    }
+    set_log(log);
+    for plugin in context.plugins() {
+        plugin.handler().on_reset(plugin.desc());
+    }
     PluginReturn::success()
 }
 
+/// Tear down all the global state, a later `init` starts clean.
 pub fn clear() -> PluginReturn {
-    *INIT_DATA.lock().unwrap() = None;
+    clear_state();
+    // Closed last, the lines of the teardown are written.
+    plog_file::clear_file_log();
     PluginReturn::success()
 }
 
-fn set_init_data(info: *const InitData) -> PluginReturn {
+fn clear_state() {
+    if let Some(context) = get_context() {
+        for plugin in context.plugins() {
+            plugin.handler().on_clear(plugin.desc());
+        }
+    }
+    reset_state();
+}
+
+/// Tear down the global state without calling the handlers.
+fn reset_state() {
+    set_context(None);
+    stats::clear();
+    diag::clear();
+    mem::report_leaks();
+    plugin_common::plog::clear_log();
+    guard::clear_poisoned();
+}
+
+/// Bind the host log callback, the max level and the log file are read from the shared config
+/// of the first plugin.
+///
+/// The level is set first, the lines buffered before the callback are filtered by it.
+fn set_log(log: CbLog) {
+    let id = get_context().and_then(|c| Some(c.plugins().first()?.id().to_owned()));
+    let level = id
+        .as_ref()
+        .and_then(|id| call::get_conf(id, "", CONF_KEY_LOG_LEVEL))
+        .and_then(|level| match level.parse::<LevelFilter>() {
+            Ok(level) => Some(level),
+            Err(_) => {
+                plugin_common::warn!("Invalid {} '{}'", CONF_KEY_LOG_LEVEL, level);
+                None
+            }
+        })
+        .unwrap_or(DEFAULT_LOG_LEVEL);
+    plog::init_logger(level);
+    if let Some(id) = id.as_deref() {
+        let mode = match call::get_conf(id, "", CONF_KEY_LOG_TO_FILE) {
+            Some(v) if v == "1" => FileMode::Mirror,
+            _ => FileMode::Fallback,
+        };
+        init_file_log(id);
+        plog_file::set_file_mode(mode);
+    }
+    #[cfg(feature = "tracing")]
+    plugin_common::ptrace::init_tracing();
+    plog::set_log(log);
+}
+
+/// Set the log file of the plugin in `FileMode::Fallback`, if it is not set yet.
+///
+/// The config is not readable before the init data is loaded, the mode is set by `set_log`.
+fn init_file_log(id: &str) {
+    let Some(dir) = data::log_dir(id) else {
+        return;
+    };
+    if plog_file::file_log_path() != Some(dir.join(plog_file::LOG_FILE_NAME)) {
+        plog_file::set_file_log(FileLog::new(dir, FileMode::Fallback));
+    }
+}
+
+fn load_init_data(
+    descs: &[&desc::Desc],
+    info: *const InitData,
+) -> Result<(InitData, Option<Version>), PluginReturn> {
     unsafe {
         if info.is_null() || (*info).version.is_null() {
-            return PluginReturn::new(
-                crate::errno::ERR_PLUGIN_MSG_INIT_INVALID,
-                "Invalid InitData, null pointer",
-            );
+            return Err(invalid_init_data("Invalid InitData, null pointer"));
+        }
+        // Always parsed for the handlers, a non-semver version is refused only if a range is
+        // declared.
+        let host_version = cstr_to_string((*info).version).and_then(|v| desc::parse_version(&v));
+        if !descs.iter().any(|d| d.has_host_version_range()) {
+            return Ok(((*info).clone(), host_version.ok()));
+        }
+        let host_version = match host_version {
+            Ok(v) => v,
+            Err(e) => {
+                return Err(invalid_init_data(&format!("Invalid host version, {}", e)));
+            }
+        };
+        for desc in descs {
+            if let Err(e) = desc.check_host_version(&host_version) {
+                return Err(invalid_init_data(&format!(
+                    "Incompatible host version, {}",
+                    e
+                )));
+            }
         }
-        *INIT_DATA.lock().unwrap() = Some((*info).clone());
+        Ok(((*info).clone(), Some(host_version)))
+    }
+}
+
+/// Logged too, the log file keeps the failures of the init.
+fn invalid_init_data(msg: &str) -> PluginReturn {
+    plugin_common::error!("{}", msg);
+    PluginReturn::new(crate::errno::ERR_PLUGIN_MSG_INIT_INVALID, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{desc::Desc, errno::*, handler::*};
+    use std::sync::Mutex;
+
+    static CALLED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    struct TestHandler;
+
+    impl handler::Handler for TestHandler {
+        fn on_init(&self, d: &Desc) -> ResultType<()> {
+            CALLED.lock().unwrap().push(format!("{} init", d.id));
+            if d.id == "fail" {
+                plugin_common::bail!("init failed");
+            }
+            Ok(())
+        }
+
+        fn on_reset(&self, d: &Desc) {
+            CALLED.lock().unwrap().push(format!("{} reset", d.id));
+        }
+
+        fn on_clear(&self, d: &Desc) {
+            CALLED.lock().unwrap().push(format!("{} clear", d.id));
+        }
+
+        fn handle_ui_event(&self, d: &Desc, _local: String, msg_ui: MsgFromUi) -> HandlerRet {
+            CALLED
+                .lock()
+                .unwrap()
+                .push(format!("{} ui {}", d.id, msg_ui.key));
+            HandlerRet::success()
+        }
+
+        fn handle_client_event(
+            &self,
+            _d: &Desc,
+            _args: *const c_void,
+            _len: usize,
+            _out: *mut *mut c_void,
+            _out_len: *mut usize,
+        ) -> HandlerRet {
+            HandlerRet::default()
+        }
+
+        fn handle_server_event(&self, d: &Desc, _args: *const c_void, _len: usize) -> HandlerRet {
+            CALLED.lock().unwrap().push(format!("{} peer", d.id));
+            HandlerRet::success()
+        }
+
+        fn handle_listen_event(
+            &self,
+            d: &Desc,
+            _local: String,
+            _remote: &str,
+            event: MsgListenEvent,
+        ) -> HandlerRet {
+            CALLED
+                .lock()
+                .unwrap()
+                .push(format!("{} listen {}", d.id, event.event));
+            HandlerRet::success()
+        }
+    }
+
+    extern "C" fn msg(
+        _peer: *const c_char,
+        _target: *const c_char,
+        _id: *const c_char,
+        _content: *const c_void,
+        _len: usize,
+    ) -> PluginReturn {
+        PluginReturn::success()
+    }
+
+    extern "C" fn get_conf(
+        _peer: *const c_char,
+        _id: *const c_char,
+        _key: *const c_char,
+    ) -> *const c_char {
+        std::ptr::null()
+    }
+
+    extern "C" fn get_id() -> *const c_char {
+        str_to_cstr_ret("local peer id")
+    }
+
+    extern "C" fn log(_level: *const c_char, _msg: *const c_char) {}
+
+    extern "C" fn native(
+        _method: *const c_char,
+        _json: *const c_char,
+        _raw: *const c_void,
+        _raw_len: usize,
+    ) -> NativeReturnValue {
+        NativeReturnValue {
+            return_type: 0,
+            data: std::ptr::null(),
+        }
+    }
+
+    fn new_desc(id: &str) -> Desc {
+        Desc {
+            id: id.to_owned(),
+            name: id.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn call(method: &str, args: &str) -> PluginReturn {
+        let method = format!("{}\0", method);
+        let args = format!("{}\0", args);
+        call::plugin_call(
+            method.as_ptr() as _,
+            c"remote peer id".as_ptr(),
+            args.as_ptr() as _,
+            args.len(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    }
+
+    #[test]
+    fn test_init_plugins() {
+        let dir = std::env::temp_dir().join(format!("plugin_init_test_{}", std::process::id()));
+        std::env::set_var(data::ENV_DATA_DIR, &dir);
+        plugin_common::warn!("logged before init");
+        let init_data = InitData {
+            version: str_to_cstr_ret("1.2.0"),
+            cbs: Callbacks {
+                msg,
+                get_conf,
+                get_id,
+                log,
+                native,
+            },
+        };
+
+        let duplicated = vec![
+            (
+                Box::new(TestHandler) as Box<dyn handler::Handler>,
+                new_desc("a"),
+            ),
+            (
+                Box::new(TestHandler) as Box<dyn handler::Handler>,
+                new_desc("a"),
+            ),
+        ];
+        assert!(!init_plugins(duplicated, &init_data).is_success());
+        // The log callback is not bound, the failure is kept in the log file.
+        let log_file = dir.join("a").join("logs").join(plog_file::LOG_FILE_NAME);
+        let content = std::fs::read_to_string(&log_file).unwrap();
+        assert!(content.contains("logged before init"), "{}", content);
+        assert!(content.contains("Duplicated plugin id a"), "{}", content);
+
+        let plugins = vec![
+            (
+                Box::new(TestHandler) as Box<dyn handler::Handler>,
+                new_desc("a"),
+            ),
+            (
+                Box::new(TestHandler) as Box<dyn handler::Handler>,
+                new_desc("b"),
+            ),
+        ];
+        assert!(init_plugins(plugins, &init_data).is_success());
+
+        let ui = r#"{"id":"b","name":"b","location":"host|main|settings|plugin","key":"k","value":"1","action":""}"#;
+        assert!(call("handle_ui", ui).is_success());
+        let peer = r#"{"id":"a","name":"a","method":"m","content":""}"#;
+        assert!(call("handle_peer", peer).is_success());
+        let unknown = r#"{"id":"c","name":"c","method":"m","content":""}"#;
+        assert_eq!(
+            call("handle_peer", unknown).get_code_msg().0,
+            ERR_PEER_ID_MISMATCH
+        );
+        assert!(call("handle_listen_event", r#"{"event":"e"}"#).is_success());
+
+        // The single plugin API works on the first plugin.
+        #[allow(deprecated)]
+        {
+            assert!(get_init_data().lock().unwrap().is_some());
+            let first = handler::get_handler().unwrap();
+            handler::set_handler(Box::new(TestHandler));
+            let replaced = handler::get_handler().unwrap();
+            assert!(!Arc::ptr_eq(&first, &replaced));
+            assert_eq!(get_context().unwrap().plugins()[1].id(), "b");
+        }
+
+        // The host features are kept by reset.
+        assert!(call("set_host_features", r#"{"json_errors":true}"#).is_success());
+        assert!(reset(&init_data).is_success());
+        assert!(get_context().unwrap().host_features().json_errors);
+        assert!(clear().is_success());
+        assert!(get_context().is_none());
+        #[allow(deprecated)]
+        {
+            assert!(get_init_data().lock().unwrap().is_none());
+            assert!(handler::get_handler().is_none());
+        }
+        assert!(plugin_common::plog::__get_log().is_none());
+        assert!(plog_file::file_log_path().is_none());
+        assert!(!call("handle_listen_event", r#"{"event":"e"}"#).is_success());
+
+        assert_eq!(
+            *CALLED.lock().unwrap(),
+            vec![
+                "a init",
+                "b init",
+                "b ui k",
+                "a peer",
+                "a listen e",
+                "b listen e",
+                "a reset",
+                "b reset",
+                "a clear",
+                "b clear"
+            ]
+        );
+
+        // The host version is not checked without a range, it may not be a semver.
+        let init_data = InitData {
+            version: str_to_cstr_ret("test version"),
+            cbs: init_data.cbs,
+        };
+        let plugins = vec![(
+            Box::new(TestHandler) as Box<dyn handler::Handler>,
+            new_desc("a"),
+        )];
+        assert!(init_plugins(plugins, &init_data).is_success());
+        assert!(get_host_version().is_none());
+        assert!(clear().is_success());
+        // The host version is parsed for the handlers without a range too.
+        let semver_data = InitData {
+            version: str_to_cstr_ret("1.2.0"),
+            cbs: init_data.cbs,
+        };
+        let plugins = vec![(
+            Box::new(TestHandler) as Box<dyn handler::Handler>,
+            new_desc("a"),
+        )];
+        assert!(init_plugins(plugins, &semver_data).is_success());
+        assert_eq!(get_host_version(), Some(Version::new(1, 2, 0)));
+        let mut desc = new_desc("a");
+        desc.min_host_version = Some("1.0.0".to_owned());
+        let plugins = vec![(Box::new(TestHandler) as Box<dyn handler::Handler>, desc)];
+        assert_eq!(
+            init_plugins(plugins, &init_data).get_code_msg().0,
+            ERR_PLUGIN_MSG_INIT_INVALID
+        );
+        assert!(clear().is_success());
+
+        // Only the plugins inited before the failure are cleared.
+        CALLED.lock().unwrap().clear();
+        let plugins = vec![
+            (
+                Box::new(TestHandler) as Box<dyn handler::Handler>,
+                new_desc("a"),
+            ),
+            (
+                Box::new(TestHandler) as Box<dyn handler::Handler>,
+                new_desc("fail"),
+            ),
+            (
+                Box::new(TestHandler) as Box<dyn handler::Handler>,
+                new_desc("c"),
+            ),
+        ];
+        assert_eq!(
+            init_plugins(plugins, &semver_data).get_code_msg().0,
+            ERR_PLUGIN_MSG_INIT_FAILED
+        );
+        assert!(get_context().is_none());
+        assert!(plog_file::file_log_path().is_none());
+        assert_eq!(
+            *CALLED.lock().unwrap(),
+            vec!["a init", "fail init", "a clear"]
+        );
+        std::fs::remove_dir_all(&dir).ok();
     }
-    PluginReturn::success()
 }
@@ -1,16 +1,28 @@
 use errno::ERR_SUCCESS;
-use plugin_common::{lazy_static::lazy_static, libc, CbLog, ResultType};
+use plugin_common::{anyhow::anyhow, bail, CbLog, ResultType};
 use std::{
     ffi::{c_char, c_int, c_void, CStr},
     ptr::null,
-    sync::{Arc, Mutex},
 };
 
 pub mod call;
+pub mod context;
+pub mod data;
 pub mod desc;
+pub mod diag;
 pub mod errno;
+pub mod error;
+pub mod guard;
 pub mod handler;
 pub mod init;
+pub mod mem;
+pub mod method;
+pub mod migration;
+pub mod policy;
+pub mod stats;
+pub mod storage;
+
+pub use mem::{str_to_cstr, str_to_cstr_ret};
 
 /// Callback to send message to peer or ui.
 /// peer, target, id are utf8 strings(null terminated).
@@ -78,7 +90,7 @@ pub struct Callbacks {
 ///
 /// [Note]
 /// The msg must be nullptr if code is errno::ERR_SUCCESS.
-/// The msg must be freed by caller if code is not errno::ERR_SUCCESS.
+/// The msg must be freed by caller if code is not errno::ERR_SUCCESS, with the exported `plugin_free`.
 #[repr(C)]
 #[derive(Debug)]
 pub struct PluginReturn {
@@ -112,47 +124,98 @@ impl PluginReturn {
         } else {
             assert!(!self.msg.is_null());
             let msg = cstr_to_string(self.msg).unwrap_or_default();
-            unsafe {
-                libc::free(self.msg as _);
-            }
+            mem::free(self.msg as _);
             self.msg = null();
             (self.code as _, msg)
         }
     }
 }
 
+/// Decode the null terminated string.
+///
+/// The pointer passed by the host is trusted to be null or a null terminated string.
 #[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub fn cstr_to_string(cstr: *const c_char) -> ResultType<String> {
+    if cstr.is_null() {
+        bail!("null pointer");
+    }
     Ok(String::from_utf8(unsafe {
         CStr::from_ptr(cstr).to_bytes().to_vec()
     })?)
 }
 
-#[inline]
-pub fn str_to_cstr(s: &str, out: *mut *mut c_char, out_buf_len: *mut usize) {
-    let s = s.as_bytes();
-    unsafe {
-        *out = libc::malloc(s.len()) as *mut c_char;
-        *out_buf_len = s.len();
-        libc::memcpy(
-            *out as *mut libc::c_void,
-            s.as_ptr() as *const libc::c_void,
-            s.len(),
-        );
+/// Decode the null terminated string, at most `max_len` bytes are read, including the trailing 0.
+///
+/// The pointer passed by the host is trusted to be null or readable up to the trailing 0
+/// or `max_len` bytes.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn cstr_to_string_bounded(cstr: *const c_char, max_len: usize) -> ResultType<String> {
+    if cstr.is_null() {
+        bail!("null pointer");
+    }
+    let mut len = 0;
+    while len < max_len && unsafe { *cstr.add(len) } != 0 {
+        len += 1;
     }
+    if len == max_len {
+        bail!("unterminated string, no trailing 0 in {} bytes", max_len);
+    }
+    let b = unsafe { std::slice::from_raw_parts(cstr as *const u8, len) };
+    Ok(std::str::from_utf8(b)
+        .map_err(|e| anyhow!("invalid utf-8, {}", e))?
+        .to_owned())
 }
 
-#[inline]
-pub fn str_to_cstr_ret(s: &str) -> *const c_char {
-    let mut s = s.as_bytes().to_vec();
-    s.push(0);
-    unsafe {
-        let r = libc::malloc(s.len()) as *mut c_char;
-        libc::memcpy(
-            r as *mut libc::c_void,
-            s.as_ptr() as *const libc::c_void,
-            s.len(),
-        );
-        r
+/// Decode the args of `len` bytes, the trailing 0 is optional.
+pub fn args_to_string(args: *const c_void, len: usize) -> ResultType<String> {
+    if args.is_null() {
+        bail!("null pointer");
+    }
+    let b = unsafe { std::slice::from_raw_parts(args as *const u8, len) };
+    let b = b.strip_suffix(&[0]).unwrap_or(b);
+    if let Some(i) = b.iter().position(|c| *c == 0) {
+        bail!("unexpected 0 at {} of {} bytes", i, len);
+    }
+    Ok(std::str::from_utf8(b)
+        .map_err(|e| anyhow!("invalid utf-8, {}", e))?
+        .to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert!(cstr_to_string(null()).is_err());
+
+        let s = c"handle_ui";
+        assert_eq!(cstr_to_string_bounded(s.as_ptr(), 10).unwrap(), "handle_ui");
+        assert!(cstr_to_string_bounded(s.as_ptr(), 9)
+            .unwrap_err()
+            .to_string()
+            .contains("unterminated"));
+        assert!(cstr_to_string_bounded(null(), 10).is_err());
+        let invalid = b"\xff\xfe\0";
+        assert!(cstr_to_string_bounded(invalid.as_ptr() as _, 10)
+            .unwrap_err()
+            .to_string()
+            .contains("utf-8"));
+
+        // Only `len` bytes are read, the trailing 0 is optional.
+        let args = b"{}\0garbage";
+        assert_eq!(args_to_string(args.as_ptr() as _, 2).unwrap(), "{}");
+        assert_eq!(args_to_string(args.as_ptr() as _, 3).unwrap(), "{}");
+        assert_eq!(args_to_string(args.as_ptr() as _, 0).unwrap(), "");
+        assert!(args_to_string(args.as_ptr() as _, 4)
+            .unwrap_err()
+            .to_string()
+            .contains("unexpected 0"));
+        assert!(args_to_string(null(), 2).is_err());
+        assert!(args_to_string(invalid.as_ptr() as _, 2)
+            .unwrap_err()
+            .to_string()
+            .contains("utf-8"));
     }
 }
@@ -0,0 +1,229 @@
+//! The memory crossing the boundary between the host and the plugin.
+//!
+//! All the buffers passed to the host (`PluginReturn.msg`, the result of `desc()`, the `out` buffer
+//! of `server_call`) are allocated here, with the C allocator, not the Rust global allocator.
+//! So the plugin can use a custom global allocator, and the host can free the buffers with
+//! the exported `plugin_free` instead of relying on a shared `libc::free`.
+//!
+//! With the `mem-track` feature, every allocation is recorded with a backtrace, and the
+//! outstanding ones are reported at `clear()`, or on demand by `report_leaks`.
+
+use plugin_common::libc;
+use std::{
+    ffi::{c_char, c_void},
+    ptr::null_mut,
+};
+
+/// Allocate `len` bytes for the host.
+#[inline]
+pub fn alloc(len: usize) -> *mut c_void {
+    // `malloc(0)` may return null, keep the result a valid pointer.
+    let ptr = unsafe { libc::malloc(len.max(1)) };
+    #[cfg(feature = "mem-track")]
+    track::on_alloc(ptr, len);
+    ptr
+}
+
+/// Free the buffer allocated by `alloc` or passed from the host. Null is ignored.
+///
+/// The pointer is trusted, it is not checked to be allocated and not freed yet.
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn free(ptr: *mut c_void) {
+    if !ptr.is_null() {
+        #[cfg(feature = "mem-track")]
+        track::on_free(ptr);
+        unsafe {
+            libc::free(ptr);
+        }
+    }
+}
+
+/// Copy the bytes to a new buffer for the host.
+#[inline]
+pub fn alloc_bytes(b: &[u8]) -> *mut c_void {
+    let ptr = alloc(b.len());
+    if !ptr.is_null() {
+        unsafe {
+            libc::memcpy(ptr, b.as_ptr() as *const c_void, b.len());
+        }
+    }
+    ptr
+}
+
+/// Copy the bytes to a new buffer for the host, and set the `out` and `out_len`.
+///
+/// The pointers passed by the host are trusted to be valid for writes.
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn bytes_to_out(b: &[u8], out: *mut *mut c_void, out_len: *mut usize) {
+    let ptr = alloc_bytes(b);
+    unsafe {
+        *out = ptr;
+        *out_len = if ptr.is_null() { 0 } else { b.len() };
+    }
+}
+
+/// Copy the string to a new buffer for the host, without the trailing 0.
+///
+/// The pointers passed by the host are trusted to be valid for writes.
+#[inline]
+pub fn str_to_cstr(s: &str, out: *mut *mut c_char, out_buf_len: *mut usize) {
+    bytes_to_out(s.as_bytes(), out as *mut *mut c_void, out_buf_len);
+}
+
+/// Copy the string to a new null terminated buffer for the host.
+#[inline]
+pub fn str_to_cstr_ret(s: &str) -> *const c_char {
+    let ptr = alloc(s.len() + 1) as *mut u8;
+    if !ptr.is_null() {
+        unsafe {
+            libc::memcpy(ptr as *mut c_void, s.as_ptr() as *const c_void, s.len());
+            *ptr.add(s.len()) = 0;
+        }
+    }
+    ptr as _
+}
+
+/// Copy the null terminated string, the result must be freed by `free`.
+///
+/// The pointer is trusted to be null or a null terminated string.
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn dup_cstr(s: *const c_char) -> *const c_char {
+    if s.is_null() {
+        return null_mut();
+    }
+    let len = unsafe { libc::strlen(s) } + 1;
+    let ptr = alloc(len);
+    if !ptr.is_null() {
+        unsafe {
+            libc::memcpy(ptr, s as *const c_void, len);
+        }
+    }
+    ptr as _
+}
+
+/// The number of the buffers allocated by `alloc` and not freed yet.
+///
+/// Always 0 without the `mem-track` feature.
+pub fn outstanding() -> usize {
+    #[cfg(feature = "mem-track")]
+    return track::outstanding();
+    #[cfg(not(feature = "mem-track"))]
+    0
+}
+
+/// Log the outstanding buffers with the backtraces of their allocations.
+///
+/// Returns the number of the outstanding buffers.
+pub fn report_leaks() -> usize {
+    #[cfg(feature = "mem-track")]
+    return track::report_leaks();
+    #[cfg(not(feature = "mem-track"))]
+    0
+}
+
+#[cfg(feature = "mem-track")]
+mod track {
+    use plugin_common::{lazy_static::lazy_static, log::Level, plog};
+    use std::{
+        backtrace::Backtrace,
+        collections::HashMap,
+        ffi::c_void,
+        sync::{Mutex, PoisonError},
+    };
+
+    struct Allocation {
+        len: usize,
+        backtrace: Backtrace,
+    }
+
+    lazy_static! {
+        static ref ALLOCATIONS: Mutex<HashMap<usize, Allocation>> = Default::default();
+    }
+
+    pub(super) fn on_alloc(ptr: *mut c_void, len: usize) {
+        if ptr.is_null() {
+            return;
+        }
+        let backtrace = Backtrace::force_capture();
+        ALLOCATIONS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(ptr as usize, Allocation { len, backtrace });
+    }
+
+    pub(super) fn on_free(ptr: *mut c_void) {
+        // The buffers allocated by the host are not recorded, ignore them.
+        ALLOCATIONS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&(ptr as usize));
+    }
+
+    pub(super) fn outstanding() -> usize {
+        ALLOCATIONS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    pub(super) fn report_leaks() -> usize {
+        // Logged out of the lock, the host log callback may call `plugin_free`.
+        let leaks = ALLOCATIONS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(ptr, a)| {
+                format!(
+                    "Leaked buffer {:#x}, {} bytes, allocated at:\n{}",
+                    ptr, a.len, a.backtrace
+                )
+            })
+            .collect::<Vec<_>>();
+        // Not rate limited, every leak is reported.
+        for leak in leaks.iter() {
+            plog::__log(Level::Warn, format_args!("{}", leak));
+        }
+        leaks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cstr_to_string;
+
+    #[test]
+    fn test_alloc() {
+        let s = str_to_cstr_ret("test str");
+        assert_eq!(cstr_to_string(s).unwrap(), "test str");
+        let d = dup_cstr(s);
+        assert_eq!(cstr_to_string(d).unwrap(), "test str");
+        free(s as _);
+        free(d as _);
+
+        let mut out = null_mut();
+        let mut out_len = 0;
+        str_to_cstr("", &mut out, &mut out_len);
+        assert!(!out.is_null());
+        assert_eq!(out_len, 0);
+        free(out as _);
+
+        free(null_mut());
+        assert!(dup_cstr(std::ptr::null()).is_null());
+    }
+
+    #[cfg(feature = "mem-track")]
+    #[test]
+    fn test_track() {
+        let s = str_to_cstr_ret("test leak");
+        assert!(track::outstanding() > 0);
+        assert!(report_leaks() > 0);
+        free(s as _);
+        // Not recorded, the buffer is allocated by the host.
+        let host = unsafe { libc::malloc(8) };
+        free(host);
+    }
+}
@@ -0,0 +1,149 @@
+//! The custom call methods of the plugin.
+//!
+//! Besides the built-in methods, a plugin can handle extra named methods, for example
+//! `get_status`. The methods are declared in `Desc::methods` for the host to discover them,
+//! and registered by `Handler::register_methods` with typed input and output.
+//!
+//! The args of the call are `MsgCall` in JSON, the output is written to `out` in JSON.
+
+use crate::{desc::Desc, error::PluginError, handler::*};
+use plugin_common::{
+    serde_derive::Deserialize,
+    serde_json::{self, Value},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+
+/// The built-in methods, the custom methods must not use these names.
+pub const BUILTIN_METHODS: [&[u8]; 6] = [
+    METHOD_HANDLE_UI,
+    METHOD_HANDLE_PEER,
+    METHOD_HANDLE_LISTEN_EVENT,
+    METHOD_SET_HOST_FEATURES,
+    METHOD_GET_STATS,
+    METHOD_GET_DIAGNOSTICS,
+];
+
+/// The args of the custom methods.
+#[derive(Debug, Deserialize)]
+pub struct MsgCall {
+    /// The id of the plugin.
+    pub id: String,
+    /// The input of the method.
+    #[serde(default)]
+    pub content: Value,
+}
+
+type MethodFn = Box<dyn Fn(&Desc, &str, Value) -> Result<Value, PluginError> + Send + Sync>;
+
+/// The registry of the custom methods of a plugin.
+#[derive(Default)]
+pub struct Methods {
+    methods: HashMap<String, MethodFn>,
+}
+
+impl Methods {
+    /// Register the method, the previous one with the same name is replaced.
+    ///
+    /// f: Called with the desc, the remote peer id and the input.
+    pub fn register<I, O, F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        I: DeserializeOwned,
+        O: Serialize,
+        F: Fn(&Desc, &str, I) -> Result<O, PluginError> + Send + Sync + 'static,
+    {
+        self.methods.insert(
+            name.to_owned(),
+            Box::new(move |d, peer, content| {
+                let input = serde_json::from_value(content)?;
+                serde_json::to_value(f(d, peer, input)?)
+                    .map_err(|e| PluginError::CallFailed(format!("serialize output, {}", e)))
+            }),
+        );
+        self
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.methods.keys().map(|k| k.as_str())
+    }
+
+    #[inline]
+    pub fn contains(&self, name: &str) -> bool {
+        self.methods.contains_key(name)
+    }
+
+    /// `None` if the method is not registered.
+    pub(crate) fn call(
+        &self,
+        name: &str,
+        d: &Desc,
+        peer: &str,
+        content: Value,
+    ) -> Option<Result<Value, PluginError>> {
+        self.methods.get(name).map(|f| f(d, peer, content))
+    }
+}
+
+/// Check the registered methods against the declared ones.
+pub(crate) fn check_methods(d: &Desc, methods: &Methods) -> Result<(), String> {
+    for name in d.methods.iter() {
+        if BUILTIN_METHODS
+            .iter()
+            .any(|m| m.strip_suffix(b"\0") == Some(name.as_bytes()))
+        {
+            return Err(format!("method '{}' of {} is built-in", name, d.id));
+        }
+        if !methods.contains(name) {
+            return Err(format!("method '{}' of {} is not registered", name, d.id));
+        }
+    }
+    let mut undeclared = methods
+        .names()
+        .filter(|name| !d.methods.iter().any(|m| m == name))
+        .collect::<Vec<_>>();
+    undeclared.sort();
+    if !undeclared.is_empty() {
+        return Err(format!(
+            "methods {:?} of {} are not declared in the desc",
+            undeclared, d.id
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errno::*;
+    use plugin_common::serde_json::json;
+
+    #[test]
+    fn test_methods() {
+        let mut d: Desc = serde_json::from_value(json!({
+            "id": "test", "name": "", "version": "", "description": "", "author": "",
+            "home": "", "license": "", "published": "", "released": "", "github": "",
+            "location": {"ui": {}}, "config": {"shared": [], "peer": []}, "listen_events": [],
+            "methods": ["add"],
+        }))
+        .unwrap();
+        let mut methods = Methods::default();
+        methods.register("add", |_d: &Desc, _peer: &str, (a, b): (i32, i32)| {
+            a.checked_add(b)
+                .ok_or_else(|| PluginError::InvalidArgs("overflow".to_owned()))
+        });
+        assert!(check_methods(&d, &methods).is_ok());
+
+        let r = methods.call("add", &d, "", json!([1, 2])).unwrap();
+        assert_eq!(r.unwrap(), json!(3));
+        let r = methods.call("add", &d, "", json!("1")).unwrap();
+        assert_eq!(r.unwrap_err().code(), ERR_CALL_INVALID_ARGS);
+        let r = methods.call("add", &d, "", json!([i32::MAX, 1])).unwrap();
+        assert_eq!(r.unwrap_err().code(), ERR_CALL_INVALID_ARGS);
+        assert!(methods.call("sub", &d, "", json!([1, 2])).is_none());
+
+        d.methods.push("handle_ui".to_owned());
+        assert!(check_methods(&d, &methods).is_err());
+        d.methods.clear();
+        assert!(check_methods(&d, &methods).is_err());
+    }
+}
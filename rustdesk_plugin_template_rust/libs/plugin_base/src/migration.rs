@@ -0,0 +1,349 @@
+//! The migrations of the shared config between the releases of the plugin.
+//!
+//! `Desc::config_version` is the version of the config schema of the release. The plugin
+//! registers the steps of each version by `Handler::register_migrations`, the steps of version
+//! `n` migrate the config from `n - 1` to `n`.
+//!
+//! The migrations run once at `init`, from the version recorded in `CONF_KEY_CONFIG_VERSION`
+//! to `Desc::config_version`. The old values are read by `get_conf` and the new ones are
+//! written by `MsgToConfig`. The version is recorded after each migrated version, so an
+//! interrupted migration resumes from the last completed version.
+//!
+//! The host can't remove a config item, the dropped keys are set to empty, and the empty
+//! values are treated as not set.
+
+use crate::{
+    call::{call_msg_cb, get_conf},
+    desc::{Desc, Permission},
+    errno::ERR_SUCCESS,
+    error::PluginError,
+    handler::*,
+};
+use std::collections::BTreeMap;
+
+/// The shared config recording the applied version, it must be declared if
+/// `Desc::config_version` is not 0.
+pub const CONF_KEY_CONFIG_VERSION: &str = "config-version";
+
+type TransformFn = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+/// A step of a migration.
+pub enum Step {
+    /// Move the value to the new key, the old key is dropped.
+    Rename { from: String, to: String },
+    /// Replace the value by the result of the function.
+    Transform { key: String, f: TransformFn },
+    /// Drop the value.
+    Drop { key: String },
+}
+
+impl Step {
+    pub fn rename(from: &str, to: &str) -> Self {
+        Self::Rename {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        }
+    }
+
+    pub fn transform<F>(key: &str, f: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        Self::Transform {
+            key: key.to_owned(),
+            f: Box::new(f),
+        }
+    }
+
+    pub fn drop(key: &str) -> Self {
+        Self::Drop {
+            key: key.to_owned(),
+        }
+    }
+
+    /// The shared config keys read or written by the step.
+    fn keys(&self) -> Vec<&str> {
+        match self {
+            Self::Rename { from, to } => vec![from, to],
+            Self::Transform { key, .. } | Self::Drop { key } => vec![key],
+        }
+    }
+}
+
+/// The registry of the migrations of a plugin.
+#[derive(Default)]
+pub struct Migrations {
+    versions: BTreeMap<u32, Vec<Step>>,
+}
+
+impl Migrations {
+    /// Register the steps migrating the config from `version - 1` to `version`.
+    ///
+    /// The steps are appended if the version is already registered.
+    pub fn add(&mut self, version: u32, steps: Vec<Step>) -> &mut Self {
+        self.versions.entry(version).or_default().extend(steps);
+        self
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.versions.is_empty()
+    }
+
+    /// Run the migrations from `current` to `target`, returns the reached version.
+    ///
+    /// get: Read the config, `None` if not set.
+    /// set: Write the config, the version is written to `CONF_KEY_CONFIG_VERSION`.
+    fn run<G, S>(&self, current: u32, target: u32, get: G, mut set: S) -> Result<u32, PluginError>
+    where
+        G: Fn(&str) -> Option<String>,
+        S: FnMut(&str, &str) -> Result<(), PluginError>,
+    {
+        let get = |key: &str| get(key).filter(|v| !v.is_empty());
+        let mut reached = current;
+        for version in current + 1..=target {
+            for step in self.versions.get(&version).into_iter().flatten() {
+                match step {
+                    Step::Rename { from, to } => {
+                        if let Some(value) = get(from) {
+                            set(to, &value)?;
+                            set(from, "")?;
+                        }
+                    }
+                    Step::Transform { key, f } => {
+                        if let Some(value) = get(key) {
+                            let new_value = f(&value);
+                            if new_value != value {
+                                set(key, &new_value)?;
+                            }
+                        }
+                    }
+                    Step::Drop { key } => {
+                        if get(key).is_some() {
+                            set(key, "")?;
+                        }
+                    }
+                }
+            }
+            set(CONF_KEY_CONFIG_VERSION, &version.to_string())?;
+            reached = version;
+        }
+        Ok(reached)
+    }
+}
+
+/// Check the registered migrations against the desc.
+pub(crate) fn check_migrations(d: &Desc, migrations: &Migrations) -> Result<(), String> {
+    if let Some(version) = migrations
+        .versions
+        .keys()
+        .find(|v| **v == 0 || **v > d.config_version)
+    {
+        return Err(format!(
+            "migration {} of {} is out of the config versions 1..={}",
+            version, d.id, d.config_version
+        ));
+    }
+    // The host only keeps the declared keys, the old keys of the renamed or dropped values
+    // must stay declared until no config of the older versions is left.
+    for (version, step) in migrations
+        .versions
+        .iter()
+        .flat_map(|(v, steps)| steps.iter().map(move |s| (v, s)))
+    {
+        if let Some(key) = step
+            .keys()
+            .into_iter()
+            .find(|key| !d.config.shared.iter().any(|c| c.key == *key))
+        {
+            return Err(format!(
+                "shared config '{}' of the migration {} of {} is not declared",
+                key, version, d.id
+            ));
+        }
+    }
+    if d.config_version > 0
+        && !d
+            .config
+            .shared
+            .iter()
+            .any(|c| c.key == CONF_KEY_CONFIG_VERSION)
+    {
+        return Err(format!(
+            "shared config '{}' of {} is not declared",
+            CONF_KEY_CONFIG_VERSION, d.id
+        ));
+    }
+    // The migrations and the version are written by `MsgToConfig`.
+    if d.config_version > 0 && !d.has_permission(Permission::WriteSharedConfig) {
+        return Err(format!(
+            "config version of {} requires the permission '{:?}'",
+            d.id,
+            Permission::WriteSharedConfig
+        ));
+    }
+    Ok(())
+}
+
+/// Migrate the shared config of the plugin to `Desc::config_version`.
+pub(crate) fn migrate(d: &Desc, migrations: &Migrations) -> Result<(), PluginError> {
+    if d.config_version == 0 {
+        return Ok(());
+    }
+    let current = match get_conf(&d.id, "", CONF_KEY_CONFIG_VERSION).filter(|v| !v.is_empty()) {
+        Some(v) => v.parse::<u32>().map_err(|e| {
+            PluginError::ConfigValue(format!("{} '{}', {}", CONF_KEY_CONFIG_VERSION, v, e))
+        })?,
+        None => 0,
+    };
+    if current > d.config_version {
+        plugin_common::warn!(
+            "The config of {} is version {}, newer than {}, it is kept as is",
+            d.id,
+            current,
+            d.config_version
+        );
+        return Ok(());
+    }
+    let reached = migrations.run(
+        current,
+        d.config_version,
+        |key| get_conf(&d.id, "", key),
+        |key, value| {
+            let msg = MsgToConfig::new_string(
+                CONFIG_TYPE_SHARED.to_owned(),
+                key.to_owned(),
+                value.to_owned(),
+                None,
+            );
+            match call_msg_cb(
+                "".to_owned(),
+                MSG_TO_CONFIG_TARGET,
+                d.id.clone(),
+                msg.as_bytes(),
+            ) {
+                (ERR_SUCCESS, _) => Ok(()),
+                (code, msg) => Err(PluginError::from_code(code, msg.clone())
+                    .unwrap_or(PluginError::CallbackFailed(msg))),
+            }
+        },
+    )?;
+    if reached > current {
+        plugin_common::info!(
+            "Migrated the config of {} from version {} to {}",
+            d.id,
+            current,
+            reached
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::desc::ConfigItem;
+    use std::{cell::RefCell, collections::HashMap};
+
+    #[test]
+    fn test_run() {
+        let mut migrations = Migrations::default();
+        migrations
+            .add(1, vec![Step::rename("allow-opt", "allow-option")])
+            .add(
+                2,
+                vec![
+                    Step::transform("allow-option", |v| {
+                        if v == "true" { "1" } else { "0" }.to_owned()
+                    }),
+                    Step::drop("legacy"),
+                ],
+            );
+        let conf = RefCell::new(HashMap::from([
+            ("allow-opt".to_owned(), "true".to_owned()),
+            ("legacy".to_owned(), "x".to_owned()),
+        ]));
+        let get = |key: &str| conf.borrow().get(key).cloned();
+        let set = |key: &str, value: &str| {
+            conf.borrow_mut().insert(key.to_owned(), value.to_owned());
+            Ok(())
+        };
+        assert_eq!(migrations.run(0, 2, get, set).unwrap(), 2);
+        let conf = conf.into_inner();
+        assert_eq!(conf["allow-opt"], "");
+        assert_eq!(conf["allow-option"], "1");
+        assert_eq!(conf["legacy"], "");
+        assert_eq!(conf[CONF_KEY_CONFIG_VERSION], "2");
+
+        // Only the versions after the current one run.
+        let conf = RefCell::new(HashMap::from([("allow-opt".to_owned(), "true".to_owned())]));
+        let get = |key: &str| conf.borrow().get(key).cloned();
+        let set = |key: &str, value: &str| {
+            conf.borrow_mut().insert(key.to_owned(), value.to_owned());
+            Ok(())
+        };
+        assert_eq!(migrations.run(1, 2, get, set).unwrap(), 2);
+        assert_eq!(conf.borrow()["allow-opt"], "true");
+        assert!(!conf.borrow().contains_key("allow-option"));
+
+        // Stopped at the failed step, the version is not recorded.
+        let set = |key: &str, _: &str| match key {
+            "allow-option" => Err(PluginError::CallbackFailed("".to_owned())),
+            _ => Ok(()),
+        };
+        let get = |_: &str| Some("true".to_owned());
+        assert!(migrations.run(0, 2, get, set).is_err());
+    }
+
+    #[test]
+    fn test_check_migrations() {
+        let mut d = Desc {
+            id: "plugin".to_owned(),
+            config_version: 1,
+            ..Default::default()
+        };
+        for key in [CONF_KEY_CONFIG_VERSION, "legacy"] {
+            d.config.shared.push(ConfigItem {
+                key: key.to_owned(),
+                default: "0".to_owned(),
+                description: "".to_owned(),
+            });
+        }
+        let mut migrations = Migrations::default();
+        migrations.add(1, vec![Step::drop("legacy")]);
+        assert!(check_migrations(&d, &migrations)
+            .unwrap_err()
+            .contains("WriteSharedConfig"));
+
+        d.permissions.push(Permission::WriteSharedConfig);
+        assert!(check_migrations(&d, &migrations).is_ok());
+        migrations.add(2, vec![Step::drop("legacy")]);
+        assert!(check_migrations(&d, &migrations).is_err());
+    }
+
+    #[test]
+    fn test_check_migrations_keys() {
+        let mut d = Desc {
+            id: "plugin".to_owned(),
+            config_version: 1,
+            permissions: vec![Permission::WriteSharedConfig],
+            ..Default::default()
+        };
+        for key in [CONF_KEY_CONFIG_VERSION, "allow-opt"] {
+            d.config.shared.push(ConfigItem {
+                key: key.to_owned(),
+                default: "0".to_owned(),
+                description: "".to_owned(),
+            });
+        }
+        let mut migrations = Migrations::default();
+        migrations.add(1, vec![Step::transform("allow-opt", |v| v.to_owned())]);
+        assert!(check_migrations(&d, &migrations).is_ok());
+
+        // The old key of a rename is not declared.
+        migrations.add(1, vec![Step::rename("allow-option", "allow-opt")]);
+        assert!(check_migrations(&d, &migrations)
+            .unwrap_err()
+            .contains("'allow-option'"));
+    }
+}
@@ -0,0 +1,268 @@
+//! The policies of the methods of `MsgPeer`, the messages from the remote peers.
+//!
+//! The policies are declared in `Desc::peer_policies`, so the host can show them, and
+//! `Handler::register_policies` may add more. The policy of the method is checked by
+//! `handle_peer` before the handler is called, the denied calls fail with
+//! `ERR_PEER_PERMISSION_DENIED`.
+//!
+//! If the plugin has any policy, the methods without a policy are refused. A method open to
+//! all the peers is declared with an empty policy.
+
+use crate::{
+    call::get_conf,
+    desc::{Desc, CONFIG_VALUE_TRUE},
+    error::PluginError,
+};
+use plugin_common::serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The conditions to accept a method from a remote peer, all of them must be met.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// The shared config keys which must be `CONFIG_VALUE_TRUE`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    require: Vec<String>,
+    /// Only the remote peers in the list are accepted, all if `None`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allow: Option<BTreeSet<String>>,
+    /// The remote peers refused, even if they are allowed.
+    #[serde(skip_serializing_if = "BTreeSet::is_empty")]
+    deny: BTreeSet<String>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The shared config must be `CONFIG_VALUE_TRUE`, the default of the desc is used if the
+    /// host has no value.
+    pub fn require(mut self, key: &str) -> Self {
+        self.require.push(key.to_owned());
+        self
+    }
+
+    /// Only the remote peers in the list are accepted.
+    pub fn allow<I, S>(mut self, peers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.allow
+            .get_or_insert_with(Default::default)
+            .extend(peers.into_iter().map(Into::into));
+        self
+    }
+
+    /// The remote peers in the list are refused, even if they are allowed.
+    pub fn deny<I, S>(mut self, peers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.deny.extend(peers.into_iter().map(Into::into));
+        self
+    }
+
+    /// get: Read the shared config, `None` if not set.
+    fn check<G>(&self, method: &str, peer: &str, get: G) -> Result<(), PluginError>
+    where
+        G: Fn(&str) -> Option<String>,
+    {
+        if self.deny.contains(peer) {
+            return Err(PluginError::PeerPermissionDenied(format!(
+                "Peer {} is denied to call {}",
+                peer, method
+            )));
+        }
+        if self
+            .allow
+            .as_ref()
+            .is_some_and(|allow| !allow.contains(peer))
+        {
+            return Err(PluginError::PeerPermissionDenied(format!(
+                "Peer {} is not allowed to call {}",
+                peer, method
+            )));
+        }
+        if let Some(key) = self
+            .require
+            .iter()
+            .find(|key| get(key).as_deref() != Some(CONFIG_VALUE_TRUE))
+        {
+            return Err(PluginError::PeerPermissionDenied(format!(
+                "{} is disabled by '{}'",
+                method, key
+            )));
+        }
+        Ok(())
+    }
+
+    /// The shared config keys which must be enabled.
+    #[inline]
+    pub fn required_keys(&self) -> &[String] {
+        &self.require
+    }
+}
+
+/// The registry of the policies of a plugin.
+#[derive(Default)]
+pub struct Policies {
+    methods: BTreeMap<String, Policy>,
+}
+
+impl Policies {
+    /// The policies declared in the desc.
+    pub(crate) fn from_desc(d: &Desc) -> Self {
+        Self {
+            methods: d.peer_policies.clone(),
+        }
+    }
+
+    /// Set the policy of the peer method, the previous one is replaced.
+    pub fn add(&mut self, method: &str, policy: Policy) -> &mut Self {
+        self.methods.insert(method.to_owned(), policy);
+        self
+    }
+
+    #[inline]
+    pub fn get(&self, method: &str) -> Option<&Policy> {
+        self.methods.get(method)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.methods.is_empty()
+    }
+}
+
+/// Check the registered policies against the desc.
+pub(crate) fn check_policies(d: &Desc, policies: &Policies) -> Result<(), String> {
+    for (method, policy) in policies.methods.iter() {
+        if let Some(key) = policy
+            .require
+            .iter()
+            .find(|key| !d.config.shared.iter().any(|c| &c.key == *key))
+        {
+            return Err(format!(
+                "shared config '{}' required by the peer method '{}' of {} is not declared",
+                key, method, d.id
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check the policy of the method called by the remote peer.
+pub(crate) fn check(
+    d: &Desc,
+    policies: &Policies,
+    method: &str,
+    peer: &str,
+) -> Result<(), PluginError> {
+    let Some(policy) = policies.get(method) else {
+        if policies.is_empty() {
+            return Ok(());
+        }
+        return Err(PluginError::PeerPermissionDenied(format!(
+            "No policy of the peer method {}",
+            method
+        )));
+    };
+    policy.check(method, peer, |key| {
+        get_conf(&d.id, "", key).or_else(|| {
+            d.config
+                .shared
+                .iter()
+                .find(|c| c.key == key)
+                .map(|c| c.default.clone())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{desc::ConfigItem, errno::*};
+
+    #[test]
+    fn test_check() {
+        let get = |key: &str| (key == "allow-opt").then(|| CONFIG_VALUE_TRUE.to_owned());
+        let code = |r: Result<(), PluginError>| r.err().map(|e| e.code());
+
+        let policy = Policy::new().require("allow-opt");
+        assert_eq!(code(policy.check("on", "a", get)), None);
+        let policy = policy.require("other-opt");
+        assert_eq!(
+            code(policy.check("on", "a", get)),
+            Some(ERR_PEER_PERMISSION_DENIED)
+        );
+
+        let policy = Policy::new().allow(["a", "b"]).deny(["b"]);
+        assert_eq!(code(policy.check("on", "a", get)), None);
+        assert_eq!(
+            code(policy.check("on", "b", get)),
+            Some(ERR_PEER_PERMISSION_DENIED)
+        );
+        assert_eq!(
+            code(policy.check("on", "c", get)),
+            Some(ERR_PEER_PERMISSION_DENIED)
+        );
+        // An empty allow list accepts no peers.
+        let policy = Policy::new().allow(Vec::<String>::new());
+        assert_eq!(
+            code(policy.check("on", "a", get)),
+            Some(ERR_PEER_PERMISSION_DENIED)
+        );
+    }
+
+    #[test]
+    fn test_check_policies() {
+        let mut d = Desc {
+            id: "plugin".to_owned(),
+            ..Default::default()
+        };
+        d.config.shared.push(ConfigItem {
+            key: "allow-opt".to_owned(),
+            default: "0".to_owned(),
+            description: "".to_owned(),
+        });
+        let mut policies = Policies::default();
+        policies.add("on", Policy::new().require("allow-opt"));
+        assert!(check_policies(&d, &policies).is_ok());
+        // Not initialized, the default of the desc is used.
+        assert_eq!(
+            check(&d, &policies, "on", "a").unwrap_err().code(),
+            ERR_PEER_PERMISSION_DENIED
+        );
+        // Refused without a policy, the plugin has policies.
+        assert_eq!(
+            check(&d, &policies, "notify_on", "a").unwrap_err().code(),
+            ERR_PEER_PERMISSION_DENIED
+        );
+        policies.add("notify_on", Policy::new());
+        assert!(check(&d, &policies, "notify_on", "a").is_ok());
+        // Not checked if the plugin has no policy.
+        assert!(check(&d, &Policies::default(), "notify_on", "a").is_ok());
+
+        policies.add("off", Policy::new().require("missing-opt"));
+        assert!(check_policies(&d, &policies).is_err());
+    }
+
+    #[test]
+    fn test_declared_policies() {
+        let d: Desc = plugin_common::serde_json::from_str(
+            r#"{"id":"plugin","name":"","version":"","description":"","author":"","home":"",
+            "license":"","published":"","released":"","github":"","location":{"ui":{}},
+            "config":{"shared":[],"peer":[]},"listen_events":[],
+            "peer_policies":{"on":{"allow":["a"]},"notify_on":{}}}"#,
+        )
+        .unwrap();
+        let policies = Policies::from_desc(&d);
+        assert!(check(&d, &policies, "on", "a").is_ok());
+        assert!(check(&d, &policies, "on", "b").is_err());
+        assert!(check(&d, &policies, "notify_on", "b").is_ok());
+        assert!(check(&d, &policies, "off", "a").is_err());
+    }
+}
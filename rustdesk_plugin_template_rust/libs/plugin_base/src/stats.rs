@@ -0,0 +1,333 @@
+//! The metrics of the calls, returned by the built-in method `get_stats`.
+//!
+//! `plugin_call` records the calls, the errors by errno, the latency and the bytes in and out,
+//! per method and per remote peer. `call_msg_cb` records the failures of `CbMsg` by target.
+//! The metrics are kept until `clear`.
+//!
+//! The method and peer keys are bounded by `MAX_KEYS`, the rest are counted as `OTHER_KEY`.
+
+use crate::errno::ERR_SUCCESS;
+use plugin_common::{
+    serde_derive::{Deserialize, Serialize},
+    serde_json,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+/// The max number of the distinct methods, or peers, recorded.
+pub const MAX_KEYS: usize = 1024;
+/// The key of the methods, or peers, beyond `MAX_KEYS`.
+pub const OTHER_KEY: &str = "_other";
+/// The upper bounds of the latency buckets, in microseconds.
+pub const LATENCY_BUCKETS_US: [u64; 9] = [
+    100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000,
+];
+
+static STATS: Mutex<Option<Stats>> = Mutex::new(None);
+
+/// The format of the output of `get_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsFormat {
+    #[default]
+    Json,
+    /// The Prometheus text exposition format.
+    Prometheus,
+}
+
+/// The args of `get_stats`, empty args are the defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MsgGetStats {
+    pub format: StatsFormat,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Histogram {
+    /// The counts of the buckets of `LATENCY_BUCKETS_US`, the last one is for the slower calls.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_us: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, d: Duration) {
+        let us = d.as_micros().min(u64::MAX as u128) as u64;
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS_US.len() + 1];
+        }
+        let i = LATENCY_BUCKETS_US
+            .iter()
+            .position(|b| us <= *b)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CallStats {
+    pub calls: u64,
+    /// The failed calls by errno.
+    pub errors: BTreeMap<i32, u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Only recorded per method.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_us: Option<Histogram>,
+}
+
+impl CallStats {
+    fn record(&mut self, code: i32, bytes_in: usize, bytes_out: usize) {
+        self.calls += 1;
+        if code != ERR_SUCCESS {
+            *self.errors.entry(code).or_default() += 1;
+        }
+        self.bytes_in += bytes_in as u64;
+        self.bytes_out += bytes_out as u64;
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    pub methods: BTreeMap<String, CallStats>,
+    pub peers: BTreeMap<String, CallStats>,
+    /// The failures of `CbMsg` by target.
+    pub msg_failures: BTreeMap<String, u64>,
+}
+
+/// The entry of the key, `OTHER_KEY` if there are too many keys.
+fn entry<'a, V: Default>(map: &'a mut BTreeMap<String, V>, key: &str) -> &'a mut V {
+    let key = if map.contains_key(key) || map.len() < MAX_KEYS {
+        key
+    } else {
+        OTHER_KEY
+    };
+    map.entry(key.to_owned()).or_default()
+}
+
+/// Record a call of `plugin_call`.
+pub(crate) fn record_call(
+    method: &str,
+    peer: &str,
+    code: i32,
+    latency: Duration,
+    bytes_in: usize,
+    bytes_out: usize,
+) {
+    let mut stats = STATS.lock().unwrap_or_else(PoisonError::into_inner);
+    let stats = stats.get_or_insert_with(Default::default);
+    let m = entry(&mut stats.methods, method);
+    m.record(code, bytes_in, bytes_out);
+    m.latency_us
+        .get_or_insert_with(Default::default)
+        .observe(latency);
+    entry(&mut stats.peers, peer).record(code, bytes_in, bytes_out);
+}
+
+/// Record a failure of `CbMsg`.
+pub(crate) fn record_msg_failure(target: &str) {
+    let mut stats = STATS.lock().unwrap_or_else(PoisonError::into_inner);
+    let stats = stats.get_or_insert_with(Default::default);
+    *entry(&mut stats.msg_failures, target) += 1;
+}
+
+/// The snapshot of the metrics.
+pub fn get_stats() -> Stats {
+    STATS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .unwrap_or_default()
+}
+
+pub(crate) fn clear() {
+    *STATS.lock().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+impl Stats {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut s = String::new();
+        for (label, map) in [("method", &self.methods), ("peer", &self.peers)] {
+            let name = format!("plugin_{}", label);
+            write_header(
+                &mut s,
+                &format!("{}_calls_total", name),
+                "counter",
+                "The calls.",
+            );
+            for (k, v) in map.iter() {
+                writeln!(
+                    s,
+                    "{}_calls_total{{{}=\"{}\"}} {}",
+                    name,
+                    label,
+                    escape(k),
+                    v.calls
+                )
+                .ok();
+            }
+            write_header(
+                &mut s,
+                &format!("{}_errors_total", name),
+                "counter",
+                "The failed calls by errno.",
+            );
+            for (k, v) in map.iter() {
+                for (code, n) in v.errors.iter() {
+                    writeln!(
+                        s,
+                        "{}_errors_total{{{}=\"{}\",errno=\"{}\"}} {}",
+                        name,
+                        label,
+                        escape(k),
+                        code,
+                        n
+                    )
+                    .ok();
+                }
+            }
+            for dir in ["in", "out"] {
+                let metric = format!("{}_bytes_{}_total", name, dir);
+                write_header(&mut s, &metric, "counter", &format!("The bytes {}.", dir));
+                for (k, v) in map.iter() {
+                    let n = if dir == "in" { v.bytes_in } else { v.bytes_out };
+                    writeln!(s, "{}{{{}=\"{}\"}} {}", metric, label, escape(k), n).ok();
+                }
+            }
+        }
+
+        let metric = "plugin_method_duration_seconds";
+        write_header(&mut s, metric, "histogram", "The latency of the handlers.");
+        for (k, v) in self.methods.iter() {
+            let Some(h) = v.latency_us.as_ref() else {
+                continue;
+            };
+            let k = escape(k);
+            let mut cumulative = 0;
+            for (i, n) in h.buckets.iter().enumerate() {
+                cumulative += n;
+                let le = match LATENCY_BUCKETS_US.get(i) {
+                    Some(us) => (*us as f64 / 1e6).to_string(),
+                    None => "+Inf".to_owned(),
+                };
+                writeln!(
+                    s,
+                    "{}_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    metric, k, le, cumulative
+                )
+                .ok();
+            }
+            writeln!(
+                s,
+                "{}_sum{{method=\"{}\"}} {}",
+                metric,
+                k,
+                h.sum_us as f64 / 1e6
+            )
+            .ok();
+            writeln!(s, "{}_count{{method=\"{}\"}} {}", metric, k, h.count).ok();
+        }
+
+        let metric = "plugin_msg_failures_total";
+        write_header(
+            &mut s,
+            metric,
+            "counter",
+            "The failures of the msg callback.",
+        );
+        for (k, n) in self.msg_failures.iter() {
+            writeln!(s, "{}{{target=\"{}\"}} {}", metric, escape(k), n).ok();
+        }
+        s
+    }
+}
+
+fn write_header(s: &mut String, metric: &str, kind: &str, help: &str) {
+    writeln!(s, "# HELP {} {}", metric, help).ok();
+    writeln!(s, "# TYPE {} {}", metric, kind).ok();
+}
+
+/// Escape the label value.
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::default();
+        let m = entry(&mut stats.methods, "handle_ui");
+        m.record(ERR_SUCCESS, 10, 0);
+        m.record(10001, 5, 3);
+        let h = m.latency_us.get_or_insert_with(Default::default);
+        h.observe(Duration::from_micros(50));
+        h.observe(Duration::from_secs(2));
+        entry(&mut stats.peers, "peer \"1\"").record(10001, 5, 3);
+        *entry(&mut stats.msg_failures, "peer") += 1;
+
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+        assert_eq!(json["methods"]["handle_ui"]["calls"], 2);
+        assert_eq!(json["methods"]["handle_ui"]["errors"]["10001"], 1);
+        assert_eq!(json["methods"]["handle_ui"]["bytes_in"], 15);
+        assert_eq!(json["methods"]["handle_ui"]["latency_us"]["count"], 2);
+        assert!(json["peers"]["peer \"1\""]["latency_us"].is_null());
+
+        let text = stats.to_prometheus();
+        assert!(text.contains("plugin_method_calls_total{method=\"handle_ui\"} 2\n"));
+        assert!(
+            text.contains("plugin_method_errors_total{method=\"handle_ui\",errno=\"10001\"} 1\n")
+        );
+        assert!(text.contains("plugin_peer_bytes_out_total{peer=\"peer \\\"1\\\"\"} 3\n"));
+        assert!(text.contains(
+            "plugin_method_duration_seconds_bucket{method=\"handle_ui\",le=\"0.0001\"} 1\n"
+        ));
+        assert!(text.contains(
+            "plugin_method_duration_seconds_bucket{method=\"handle_ui\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(text.contains("plugin_msg_failures_total{target=\"peer\"} 1\n"));
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let mut map = BTreeMap::<String, u64>::new();
+        for i in 0..MAX_KEYS + 10 {
+            *entry(&mut map, &i.to_string()) += 1;
+        }
+        assert_eq!(map.len(), MAX_KEYS + 1);
+        assert_eq!(map[OTHER_KEY], 10);
+        *entry(&mut map, "0") += 1;
+        assert_eq!(map["0"], 2);
+    }
+
+    #[test]
+    fn test_poisoned_stats() {
+        std::thread::spawn(|| {
+            let _lock = STATS.lock().unwrap();
+            panic!("poison the stats");
+        })
+        .join()
+        .unwrap_err();
+        assert!(STATS.is_poisoned());
+
+        // The calls are still recorded without panicking.
+        record_call("test_poisoned_stats", "", ERR_SUCCESS, Duration::ZERO, 0, 0);
+        record_msg_failure("peer");
+        get_stats();
+    }
+}
@@ -0,0 +1,358 @@
+//! The persistent key-value storage of the plugin.
+//!
+//! The values are any serde types, stored as JSON in the data directory of the plugin.
+//! There is a shared namespace, and a namespace per remote peer. Each namespace is a file,
+//! written to a temporary file and renamed, so a crash leaves either the old or the new content.
+//!
+//! The total size of the files is limited by the quota, the writes exceeding it fail with
+//! `PluginError::StorageQuota`. The total is scanned once and kept up to date by the writes.
+
+use crate::{data, error::PluginError};
+use plugin_common::serde_json::{self, Value};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+pub const DEFAULT_QUOTA: u64 = 16 * 1024 * 1024;
+const SHARED_FILE_NAME: &str = "shared.json";
+const PEERS_DIR_NAME: &str = "peers";
+const TMP_EXTENSION: &str = "json.tmp";
+// The max length of the hex encoded peer id in the file name, the longer ids are shortened, so
+// the file names stay in the limit of 255 bytes of most file systems.
+const MAX_PEER_NAME_LEN: usize = 200;
+const PEER_NAME_PREFIX_LEN: usize = 64;
+
+type Map = BTreeMap<String, Value>;
+
+fn io_err(action: &str, path: &Path, e: impl std::fmt::Display) -> PluginError {
+    PluginError::StorageIo(format!("{} {}, {}", action, path.display(), e))
+}
+
+/// The storage in a directory.
+///
+/// The writes of the namespaces of the same `Storage` are serialized and counted in the quota,
+/// the plugin should share one `Storage` instead of opening the directory several times.
+pub struct Storage {
+    dir: PathBuf,
+    quota: u64,
+    // The total size of the files, `None` if not scanned yet. Also serializes the writes.
+    lock: Mutex<Option<u64>>,
+}
+
+impl Storage {
+    /// Open the storage in the data directory of the plugin.
+    pub fn open(plugin_id: &str) -> Result<Self, PluginError> {
+        let dir = data::data_dir(plugin_id).ok_or_else(|| {
+            PluginError::StorageIo(format!("No data directory for plugin {}", plugin_id))
+        })?;
+        let storage = Self::with_dir(dir.join("storage"));
+        *storage.lock() = Some(storage.size()?);
+        Ok(storage)
+    }
+
+    /// The storage in the directory, it is created at the first write.
+    pub fn with_dir<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+            quota: DEFAULT_QUOTA,
+            lock: Mutex::new(None),
+        }
+    }
+
+    /// The max total size of the files in bytes.
+    pub fn quota(mut self, quota: u64) -> Self {
+        self.quota = quota;
+        self
+    }
+
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The namespace shared by all the peers.
+    pub fn shared(&self) -> Namespace<'_> {
+        Namespace {
+            storage: self,
+            path: self.dir.join(SHARED_FILE_NAME),
+        }
+    }
+
+    /// The namespace of the remote peer.
+    pub fn peer(&self, peer_id: &str) -> Namespace<'_> {
+        Namespace {
+            storage: self,
+            path: self
+                .dir
+                .join(PEERS_DIR_NAME)
+                .join(format!("{}.json", peer_file_name(peer_id))),
+        }
+    }
+
+    /// The total size is scanned again if a write panicked.
+    fn lock(&self) -> MutexGuard<'_, Option<u64>> {
+        self.lock.lock().unwrap_or_else(|e| {
+            let mut lock = PoisonError::into_inner(e);
+            *lock = None;
+            lock
+        })
+    }
+
+    /// The total size of the files in bytes, scanned from the directory.
+    pub fn size(&self) -> Result<u64, PluginError> {
+        let mut size = file_len(&self.dir.join(SHARED_FILE_NAME))?;
+        let peers = self.dir.join(PEERS_DIR_NAME);
+        let entries = match fs::read_dir(&peers) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(size),
+            Err(e) => return Err(io_err("read", &peers, e)),
+        };
+        for entry in entries {
+            let path = entry.map_err(|e| io_err("read", &peers, e))?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                size += file_len(&path)?;
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// Hex encoded, the peer id may not be a valid file name.
+///
+/// The long ids are shortened to the prefix and the hash of the whole id, the short names
+/// have no '-', so they never collide with the shortened ones.
+fn peer_file_name(peer_id: &str) -> String {
+    let name = peer_id
+        .bytes()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    if name.len() <= MAX_PEER_NAME_LEN {
+        return name;
+    }
+    format!(
+        "{}-{:032x}",
+        &name[..PEER_NAME_PREFIX_LEN],
+        fnv1a_128(peer_id.as_bytes())
+    )
+}
+
+/// The 128-bit FNV-1a hash, stable across the builds, unlike `DefaultHasher`.
+fn fnv1a_128(bytes: &[u8]) -> u128 {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+    bytes
+        .iter()
+        .fold(OFFSET, |h, b| (h ^ *b as u128).wrapping_mul(PRIME))
+}
+
+fn file_len(path: &Path) -> Result<u64, PluginError> {
+    match fs::metadata(path) {
+        Ok(m) => Ok(m.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(io_err("read", path, e)),
+    }
+}
+
+/// A namespace of the storage, the keys of the namespaces are independent.
+pub struct Namespace<'a> {
+    storage: &'a Storage,
+    path: PathBuf,
+}
+
+impl Namespace<'_> {
+    /// `None` if the key is not set.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, PluginError> {
+        let _lock = self.storage.lock();
+        match self.load()?.remove(key) {
+            Some(v) => Ok(Some(serde_json::from_value(v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> Result<(), PluginError> {
+        let value = serde_json::to_value(value)
+            .map_err(|e| PluginError::InvalidArgs(format!("serialize {}, {}", key, e)))?;
+        let mut size = self.storage.lock();
+        let mut map = self.load()?;
+        map.insert(key.to_owned(), value);
+        self.save(&map, &mut size)
+    }
+
+    /// Returns whether the key was set.
+    pub fn remove(&self, key: &str) -> Result<bool, PluginError> {
+        let mut size = self.storage.lock();
+        let mut map = self.load()?;
+        if map.remove(key).is_none() {
+            return Ok(false);
+        }
+        self.save(&map, &mut size)?;
+        Ok(true)
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>, PluginError> {
+        let _lock = self.storage.lock();
+        Ok(self.load()?.into_keys().collect())
+    }
+
+    /// Remove all the keys of the namespace.
+    pub fn clear(&self) -> Result<(), PluginError> {
+        let mut size = self.storage.lock();
+        self.remove_file(&mut size)
+    }
+
+    fn load(&self) -> Result<Map, PluginError> {
+        match fs::read(&self.path) {
+            Ok(content) => {
+                serde_json::from_slice(&content).map_err(|e| io_err("parse", &self.path, e))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Map::new()),
+            Err(e) => Err(io_err("read", &self.path, e)),
+        }
+    }
+
+    /// size: The total size of the storage, updated by the written file.
+    fn save(&self, map: &Map, size: &mut Option<u64>) -> Result<(), PluginError> {
+        if map.is_empty() {
+            return self.remove_file(size);
+        }
+        let content = serde_json::to_vec(map)
+            .map_err(|e| PluginError::InvalidArgs(format!("serialize, {}", e)))?;
+        let total = match *size {
+            Some(total) => total,
+            None => self.storage.size()?,
+        };
+        let old_len = file_len(&self.path)?;
+        let new_total = total
+            .saturating_sub(old_len)
+            .saturating_add(content.len() as u64);
+        if new_total > self.storage.quota {
+            *size = Some(total);
+            return Err(PluginError::StorageQuota(format!(
+                "{} bytes exceed the quota of {} bytes",
+                new_total, self.storage.quota
+            )));
+        }
+
+        let dir = self.path.parent().unwrap_or(&self.storage.dir);
+        fs::create_dir_all(dir).map_err(|e| io_err("create", dir, e))?;
+        let tmp = self.path.with_extension(TMP_EXTENSION);
+        let write = || -> std::io::Result<()> {
+            let mut f = File::create(&tmp)?;
+            f.write_all(&content)?;
+            f.sync_all()?;
+            fs::rename(&tmp, &self.path)
+        };
+        if let Err(e) = write() {
+            fs::remove_file(&tmp).ok();
+            *size = Some(total);
+            return Err(io_err("write", &self.path, e));
+        }
+        *size = Some(new_total);
+        // Persist the rename, not supported on all the platforms.
+        if let Ok(d) = File::open(dir) {
+            d.sync_all().ok();
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, size: &mut Option<u64>) -> Result<(), PluginError> {
+        let len = file_len(&self.path)?;
+        match fs::remove_file(&self.path) {
+            Ok(()) => {
+                *size = size.map(|total| total.saturating_sub(len));
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_err("remove", &self.path, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errno::*;
+
+    #[test]
+    fn test_storage() {
+        let dir = std::env::temp_dir().join(format!("plugin_storage_test_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let storage = Storage::with_dir(&dir).quota(200);
+
+        let shared = storage.shared();
+        assert_eq!(shared.get::<String>("token").unwrap(), None);
+        shared.set("token", &"abc").unwrap();
+        shared.set("history", &vec![1, 2, 3]).unwrap();
+        assert_eq!(
+            shared.get::<Vec<i32>>("history").unwrap(),
+            Some(vec![1, 2, 3])
+        );
+        assert_eq!(
+            shared.get::<i32>("token").unwrap_err().code(),
+            ERR_CALL_INVALID_ARGS
+        );
+
+        let peer = storage.peer("123 456/..");
+        assert_eq!(peer.get::<String>("token").unwrap(), None);
+        peer.set("token", &"def").unwrap();
+        assert_eq!(peer.keys().unwrap(), ["token"]);
+
+        let e = peer.set("big", &"x".repeat(200)).unwrap_err();
+        assert_eq!(e.code(), ERR_STORAGE_QUOTA);
+        assert_eq!(peer.get::<String>("big").unwrap(), None);
+        // The running total follows the writes without scanning the directory.
+        assert_eq!(*storage.lock(), Some(storage.size().unwrap()));
+        peer.clear().unwrap();
+        peer.set("token", &"def").unwrap();
+        assert_eq!(*storage.lock(), Some(storage.size().unwrap()));
+
+        // Persisted across the instances.
+        let storage = Storage::with_dir(&dir);
+        assert_eq!(
+            storage.shared().get::<String>("token").unwrap().as_deref(),
+            Some("abc")
+        );
+        assert!(storage.peer("123 456/..").remove("token").unwrap());
+        assert!(!storage.peer("123 456/..").remove("token").unwrap());
+        storage.shared().clear().unwrap();
+        assert_eq!(storage.size().unwrap(), 0);
+
+        // The long peer ids are shortened, and still distinct.
+        let storage = Storage::with_dir(&dir);
+        let long_a = "a".repeat(300);
+        let long_b = format!("{}b", "a".repeat(299));
+        storage.peer(&long_a).set("token", &"a").unwrap();
+        storage.peer(&long_b).set("token", &"b").unwrap();
+        assert_eq!(
+            storage
+                .peer(&long_a)
+                .get::<String>("token")
+                .unwrap()
+                .as_deref(),
+            Some("a")
+        );
+        assert_eq!(
+            storage
+                .peer(&long_b)
+                .get::<String>("token")
+                .unwrap()
+                .as_deref(),
+            Some("b")
+        );
+        assert!(peer_file_name(&long_a).len() < MAX_PEER_NAME_LEN);
+        storage.peer(&long_a).clear().unwrap();
+        storage.peer(&long_b).clear().unwrap();
+
+        fs::write(dir.join(SHARED_FILE_NAME), "{").unwrap();
+        assert_eq!(
+            storage.shared().get::<String>("token").unwrap_err().code(),
+            ERR_STORAGE_IO
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,579 @@
+//! Build time support for plugins.
+//!
+//! A plugin declares its desc in a `plugin.toml` manifest. The build script of the plugin
+//! calls [`build`], which parses and validates the manifest and generates
+//! `$OUT_DIR/plugin_manifest.rs`. The generated file contains the constants of the
+//! plugin (id, name, version, ui locations, config keys and custom methods) and the desc json.
+//!
+//! The policies of the peer methods are declared by the `[peer_policies.<method>]` tables.
+//!
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     plugin_build::build("plugin.toml");
+//! }
+//!
+//! // desc.rs
+//! include!(concat!(env!("OUT_DIR"), "/plugin_manifest.rs"));
+//! ```
+
+use plugin_base::{
+    desc::{
+        parse_version, Config, ConfigItem, Desc, Location, LocationPath, Permission, UiButton,
+        UiCheckbox, UiType,
+    },
+    method::BUILTIN_METHODS,
+    migration::CONF_KEY_CONFIG_VERSION,
+    policy::Policy,
+};
+use plugin_common::{anyhow::anyhow, bail, serde_derive::Deserialize, serde_json, ResultType};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Write,
+    path::Path,
+};
+
+pub const GENERATED_FILE_NAME: &str = "plugin_manifest.rs";
+
+const UI_TYPE_BUTTON: &str = "button";
+const UI_TYPE_CHECKBOX: &str = "checkbox";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginInfo {
+    pub id: String,
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub description: String,
+    pub author: String,
+    #[serde(default)]
+    pub home: String,
+    pub license: String,
+    pub published: String,
+    pub released: String,
+    #[serde(default)]
+    pub github: String,
+    #[serde(default)]
+    pub listen_events: Vec<String>,
+    #[serde(default)]
+    pub min_host_version: Option<String>,
+    #[serde(default)]
+    pub max_host_version: Option<String>,
+    #[serde(default)]
+    pub permissions: Vec<Permission>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub config_version: u32,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigSection {
+    #[serde(default)]
+    pub shared: Vec<ConfigItem>,
+    #[serde(default)]
+    pub peer: Vec<ConfigItem>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UiEntry {
+    pub location: LocationPath,
+    pub r#type: String,
+    pub key: String,
+    pub text: String,
+    #[serde(default)]
+    pub icon: String,
+    #[serde(default)]
+    pub tooltip: String,
+    #[serde(default)]
+    pub action: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub plugin: PluginInfo,
+    #[serde(default)]
+    pub config: ConfigSection,
+    #[serde(default)]
+    pub ui: Vec<UiEntry>,
+    #[serde(default)]
+    pub peer_policies: BTreeMap<String, Policy>,
+}
+
+impl Manifest {
+    /// Parse and validate the manifest.
+    pub fn parse(content: &str) -> ResultType<Self> {
+        let manifest: Manifest = toml::from_str(content)?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> ResultType<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read {}, {}", path.display(), e))?;
+        Self::parse(&content).map_err(|e| anyhow!("Invalid manifest {}, {}", path.display(), e))
+    }
+
+    /// Validate the manifest, all the errors are collected and reported at once.
+    pub fn validate(&self) -> ResultType<()> {
+        let mut errors = Vec::new();
+
+        if self.plugin.id.is_empty() {
+            errors.push("plugin.id must not be empty".to_owned());
+        }
+        if self.plugin.name.is_empty() {
+            errors.push("plugin.name must not be empty".to_owned());
+        }
+        let mut permissions = HashSet::new();
+        for permission in self.plugin.permissions.iter() {
+            if !permissions.insert(permission) {
+                errors.push(format!("permission '{:?}' is duplicated", permission));
+            }
+        }
+        if let Err(e) = parse_version(&self.plugin.version) {
+            errors.push(format!("plugin.version is invalid, {}", e));
+        }
+        let min_host_version =
+            self.plugin
+                .min_host_version
+                .as_ref()
+                .and_then(|min| match parse_version(min) {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        errors.push(format!("plugin.min_host_version is invalid, {}", e));
+                        None
+                    }
+                });
+        if let Some(max) = self.plugin.max_host_version.as_ref() {
+            match parse_version(max) {
+                Ok(max) => {
+                    if min_host_version.as_ref().is_some_and(|min| *min > max) {
+                        errors.push(
+                            "plugin.max_host_version is less than plugin.min_host_version"
+                                .to_owned(),
+                        );
+                    }
+                }
+                Err(e) => errors.push(format!("plugin.max_host_version is invalid, {}", e)),
+            }
+        }
+        for (field, value) in [
+            ("published", &self.plugin.published),
+            ("released", &self.plugin.released),
+        ] {
+            if let Err(e) = check_datetime(value) {
+                errors.push(format!("plugin.{} '{}' is invalid, {}", field, value, e));
+            }
+        }
+
+        let mut methods = HashSet::new();
+        for method in self.plugin.methods.iter() {
+            if method.is_empty()
+                || !method
+                    .bytes()
+                    .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+            {
+                errors.push(format!(
+                    "method '{}' is invalid, expected lowercase letters, digits and '_'",
+                    method
+                ));
+            } else if BUILTIN_METHODS
+                .iter()
+                .any(|m| m.strip_suffix(b"\0") == Some(method.as_bytes()))
+            {
+                errors.push(format!("method '{}' is built-in", method));
+            } else if !methods.insert(method) {
+                errors.push(format!("method '{}' is duplicated", method));
+            }
+        }
+
+        let mut config_keys = HashSet::new();
+        for item in self.config.shared.iter().chain(self.config.peer.iter()) {
+            if item.key.is_empty() {
+                errors.push("config key must not be empty".to_owned());
+            } else if !config_keys.insert(item.key.as_str()) {
+                errors.push(format!("config key '{}' is duplicated", item.key));
+            }
+        }
+
+        if self.plugin.config_version > 0
+            && !self
+                .config
+                .shared
+                .iter()
+                .any(|c| c.key == CONF_KEY_CONFIG_VERSION)
+        {
+            errors.push(format!(
+                "plugin.config_version requires the shared config '{}'",
+                CONF_KEY_CONFIG_VERSION
+            ));
+        }
+        if self.plugin.config_version > 0
+            && !self
+                .plugin
+                .permissions
+                .contains(&Permission::WriteSharedConfig)
+        {
+            errors.push(
+                "plugin.config_version requires the permission 'write-shared-config'".to_owned(),
+            );
+        }
+
+        for (method, policy) in self.peer_policies.iter() {
+            if method.is_empty() {
+                errors.push("peer policy method must not be empty".to_owned());
+            }
+            for key in policy.required_keys() {
+                if !self.config.shared.iter().any(|c| &c.key == key) {
+                    errors.push(format!(
+                        "peer policy '{}' requires the undeclared shared config '{}'",
+                        method, key
+                    ));
+                }
+            }
+        }
+
+        let mut locations = HashSet::new();
+        let mut ui_keys = HashSet::new();
+        for ui in self.ui.iter() {
+            if !locations.insert(&ui.location) {
+                errors.push(format!("ui location '{}' is duplicated", ui.location));
+            }
+            if ui.r#type != UI_TYPE_BUTTON && ui.r#type != UI_TYPE_CHECKBOX {
+                errors.push(format!(
+                    "ui '{}' has unknown type '{}', expected '{}' or '{}'",
+                    ui.key, ui.r#type, UI_TYPE_BUTTON, UI_TYPE_CHECKBOX
+                ));
+            }
+            if !ui_keys.insert(ui.key.as_str()) {
+                errors.push(format!("ui key '{}' is duplicated", ui.key));
+            }
+            if !config_keys.contains(ui.key.as_str()) {
+                errors.push(format!(
+                    "ui key '{}' is not backed by a config item",
+                    ui.key
+                ));
+            }
+        }
+
+        let mut const_names = HashMap::new();
+        let locations = self
+            .location_items()
+            .into_iter()
+            .map(|(name, location)| (name, location.to_string()));
+        for (name, value) in self.const_items().into_iter().chain(locations) {
+            if let Some(prev) = const_names.insert(name.clone(), value.clone()) {
+                errors.push(format!(
+                    "'{}' and '{}' generate the same constant {}",
+                    prev, value, name
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            bail!("{}", errors.join("; "))
+        }
+    }
+
+    pub fn to_desc(&self) -> Desc {
+        let p = &self.plugin;
+        Desc {
+            id: p.id.clone(),
+            name: p.name.clone(),
+            version: p.version.clone(),
+            description: p.description.clone(),
+            author: p.author.clone(),
+            home: p.home.clone(),
+            license: p.license.clone(),
+            published: p.published.clone(),
+            released: p.released.clone(),
+            github: p.github.clone(),
+            location: Location {
+                ui: self
+                    .ui
+                    .iter()
+                    .map(|ui| (ui.location.clone(), ui.to_ui_type()))
+                    .collect(),
+            },
+            config: Config {
+                shared: self.config.shared.iter().map(clone_config_item).collect(),
+                peer: self.config.peer.iter().map(clone_config_item).collect(),
+            },
+            listen_events: p.listen_events.clone(),
+            min_host_version: p.min_host_version.clone(),
+            max_host_version: p.max_host_version.clone(),
+            permissions: p.permissions.clone(),
+            methods: p.methods.clone(),
+            config_version: p.config_version,
+            peer_policies: self.peer_policies.clone(),
+        }
+    }
+
+    /// Generate the rust source of the constants and the desc json.
+    pub fn to_rust(&self) -> ResultType<String> {
+        let mut s = String::new();
+        writeln!(
+            s,
+            "// Generated by plugin_build from the plugin manifest, do not edit."
+        )?;
+        writeln!(s)?;
+        let mut items = vec![
+            ("ID".to_owned(), self.plugin.id.clone()),
+            ("NAME".to_owned(), self.plugin.name.clone()),
+            ("VERSION".to_owned(), self.plugin.version.clone()),
+        ];
+        items.extend(self.const_items());
+        items.push((
+            "DESC_JSON".to_owned(),
+            serde_json::to_string(&self.to_desc())?,
+        ));
+        for (name, value) in items {
+            // Not all the constants are used by the plugin.
+            writeln!(s, "#[allow(dead_code)]")?;
+            writeln!(s, "pub const {}: &str = {:?};", name, value)?;
+        }
+        for (name, location) in self.location_items() {
+            writeln!(s, "#[allow(dead_code)]")?;
+            writeln!(
+                s,
+                "pub const {}: plugin_base::desc::LocationPath = plugin_base::desc::LocationPath::from_static(plugin_base::desc::Side::{:?}, plugin_base::desc::Window::{:?}, {:?});",
+                name,
+                location.side(),
+                location.window(),
+                location.placement()
+            )?;
+        }
+        Ok(s)
+    }
+
+    fn const_items(&self) -> Vec<(String, String)> {
+        let mut items = Vec::new();
+        for item in self.config.shared.iter().chain(self.config.peer.iter()) {
+            items.push((const_name("KEY", &item.key), item.key.clone()));
+        }
+        for method in self.plugin.methods.iter() {
+            items.push((const_name("METHOD", method), method.clone()));
+        }
+        items
+    }
+
+    fn location_items(&self) -> Vec<(String, &LocationPath)> {
+        self.ui
+            .iter()
+            .map(|ui| {
+                (
+                    const_name("LOCATION", &ui.location.to_string()),
+                    &ui.location,
+                )
+            })
+            .collect()
+    }
+}
+
+impl UiEntry {
+    fn to_ui_type(&self) -> UiType {
+        if self.r#type == UI_TYPE_BUTTON {
+            UiType::Button(UiButton {
+                key: self.key.clone(),
+                text: self.text.clone(),
+                icon: self.icon.clone(),
+                tooltip: self.tooltip.clone(),
+                action: self.action.clone(),
+            })
+        } else {
+            UiType::Checkbox(UiCheckbox {
+                key: self.key.clone(),
+                text: self.text.clone(),
+                tooltip: self.tooltip.clone(),
+                action: self.action.clone(),
+            })
+        }
+    }
+}
+
+/// Parse, validate and generate the manifest for the build script.
+///
+/// Panics with the validation errors to fail the build.
+pub fn build<P: AsRef<Path>>(manifest_path: P) {
+    let manifest_path = manifest_path.as_ref();
+    println!("cargo:rerun-if-changed={}", manifest_path.display());
+    let out_dir = std::env::var("OUT_DIR").expect("OUT_DIR is not set, not in a build script?");
+    if let Err(e) = generate(manifest_path, Path::new(&out_dir).join(GENERATED_FILE_NAME)) {
+        panic!("{}", e);
+    }
+}
+
+pub fn generate<P: AsRef<Path>, Q: AsRef<Path>>(manifest_path: P, out_path: Q) -> ResultType<()> {
+    let manifest = Manifest::load(manifest_path)?;
+    std::fs::write(out_path, manifest.to_rust()?)?;
+    Ok(())
+}
+
+fn clone_config_item(item: &ConfigItem) -> ConfigItem {
+    ConfigItem {
+        key: item.key.clone(),
+        default: item.default.clone(),
+        description: item.description.clone(),
+    }
+}
+
+/// Check the datetime of format "YYYY-MM-DD HH:MM:SS" or "YYYY-MM-DD".
+fn check_datetime(s: &str) -> ResultType<()> {
+    let (date, time) = match s.split_once(' ') {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+    let date =
+        parse_fields(date, '-', 3).ok_or_else(|| anyhow!("expected 'YYYY-MM-DD HH:MM:SS'"))?;
+    let (year, month, day) = (date[0], date[1], date[2]);
+    let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+    let days = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => bail!("invalid month {}", month),
+    };
+    if day == 0 || day > days {
+        bail!("invalid day {}", day);
+    }
+    if let Some(time) = time {
+        let time = parse_fields(time, ':', 3).ok_or_else(|| anyhow!("expected 'HH:MM:SS'"))?;
+        if time[0] > 23 || time[1] > 59 || time[2] > 59 {
+            bail!("invalid time");
+        }
+    }
+    Ok(())
+}
+
+fn parse_fields(s: &str, sep: char, n: usize) -> Option<Vec<u32>> {
+    let fields = s
+        .split(sep)
+        .map(|f| {
+            if f.is_empty() || !f.bytes().all(|b| b.is_ascii_digit()) {
+                None
+            } else {
+                f.parse::<u32>().ok()
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    (fields.len() == n).then_some(fields)
+}
+
+fn const_name(prefix: &str, value: &str) -> String {
+    let mut name = prefix.to_owned();
+    for part in value.split(|c: char| !c.is_ascii_alphanumeric()) {
+        if !part.is_empty() {
+            name.push('_');
+            name.push_str(&part.to_ascii_uppercase());
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = r#"
+[plugin]
+id = "TestId"
+name = "Test"
+version = "v0.1.0"
+author = "RustDesk"
+license = "MIT"
+published = "2020-02-03 13:05:02"
+released = "2023-02-03"
+min_host_version = "1.2.0"
+permissions = ["write-shared-config"]
+methods = ["get_status"]
+
+[[config.shared]]
+key = "allow-opt"
+default = "0"
+description = "Allow option"
+
+[[ui]]
+location = "host|main|settings|plugin"
+type = "checkbox"
+key = "allow-opt"
+text = "Allow option"
+
+[peer_policies.on]
+require = ["allow-opt"]
+deny = ["blocked peer"]
+"#;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+        let desc = manifest.to_desc();
+        assert_eq!(desc.id, "TestId");
+        let location: LocationPath = "host|main|settings|plugin".parse().unwrap();
+        assert!(desc.location.ui.contains_key(&location));
+        let src = manifest.to_rust().unwrap();
+        assert!(src.contains(
+            "pub const LOCATION_HOST_MAIN_SETTINGS_PLUGIN: plugin_base::desc::LocationPath"
+        ));
+        assert!(src.contains("pub const KEY_ALLOW_OPT: &str = \"allow-opt\";"));
+        assert_eq!(desc.methods, vec!["get_status".to_owned()]);
+        assert!(src.contains("pub const METHOD_GET_STATUS: &str = \"get_status\";"));
+        assert_eq!(
+            desc.peer_policies["on"].required_keys(),
+            ["allow-opt".to_owned()]
+        );
+    }
+
+    #[test]
+    fn test_invalid_manifest() {
+        let invalid = [
+            ("version = \"v0.1.0\"", "version = \"0.1\""),
+            ("released = \"2023-02-03\"", "released = \"2023-02-30\""),
+            ("min_host_version = \"1.2.0\"", "min_host_version = \"1.2\""),
+            (
+                "min_host_version = \"1.2.0\"",
+                "min_host_version = \"1.2.0\"\nmax_host_version = \"1.1.0\"",
+            ),
+            ("host|main|settings", "server|main|settings"),
+            ("host|main|settings|plugin", "host|main"),
+            ("type = \"checkbox\"", "type = \"radio\""),
+            ("[\"write-shared-config\"]", "[\"write-all\"]"),
+            ("key = \"allow-opt\"\ntext", "key = \"other-opt\"\ntext"),
+            ("[\"get_status\"]", "[\"handle_ui\"]"),
+            ("[\"get_status\"]", "[\"get-status\"]"),
+            ("[\"get_status\"]", "[\"get_status\", \"get_status\"]"),
+            ("[\"get_status\"]", "[\"get_status\"]\nconfig_version = 1"),
+            ("require = [\"allow-opt\"]", "require = [\"other-opt\"]"),
+            ("deny = [", "block = ["),
+        ];
+        for (from, to) in invalid {
+            let content = MANIFEST.replace(from, to);
+            assert!(Manifest::parse(&content).is_err(), "{} -> {}", from, to);
+        }
+
+        let duplicated = format!(
+            "{}\n[[config.peer]]\nkey = \"allow-opt\"\ndefault = \"0\"\ndescription = \"\"\n",
+            MANIFEST
+        );
+        assert!(Manifest::parse(&duplicated).is_err());
+    }
+
+    #[test]
+    fn test_config_version_permission() {
+        let versioned = format!(
+            "{}\n[[config.shared]]\nkey = \"{}\"\ndefault = \"0\"\ndescription = \"\"\n",
+            MANIFEST.replace("[\"get_status\"]", "[\"get_status\"]\nconfig_version = 1"),
+            CONF_KEY_CONFIG_VERSION
+        );
+        assert!(Manifest::parse(&versioned).is_ok());
+        let content = versioned.replace("[\"write-shared-config\"]", "[]");
+        assert!(Manifest::parse(&content).is_err());
+    }
+}
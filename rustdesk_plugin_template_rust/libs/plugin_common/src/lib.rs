@@ -2,10 +2,17 @@ pub use anyhow::{self, bail};
 pub use lazy_static;
 pub use libc;
 pub use log;
+pub use semver;
 pub use serde_derive;
 pub use serde_json;
+#[cfg(feature = "tracing")]
+pub use tracing;
 
 pub mod plog;
+pub mod plog_file;
+pub mod plog_limit;
+#[cfg(feature = "tracing")]
+pub mod ptrace;
 pub use plog::CbLog;
 
 pub type ResultType<F, E = anyhow::Error> = anyhow::Result<F, E>;
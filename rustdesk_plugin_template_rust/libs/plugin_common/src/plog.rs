@@ -1,4 +1,29 @@
-use std::ffi::c_char;
+//! Logging through the host log callback.
+//!
+//! The `trace!`..`error!` macros of this module and the `log` crate records,
+//! from the plugin or its dependencies, are forwarded to `CbLog` once it is set by `set_log`.
+//! `init_logger` installs the `log` backend, the records above `max_level` are dropped.
+//! With the `tracing` feature and `ptrace::init_tracing`, the lines are sent as `tracing`
+//! events, so they are prefixed by the current spans. The events of the `tracing` macros are
+//! written with the same max level and rate limit.
+//!
+//! The lines logged before `set_log`, or after `clear_log`, are kept in a bounded buffer with
+//! their levels and timestamps, and flushed through `CbLog` once it is set.
+//! The lines can also be written to a local file by `plog_file`.
+//! The lines are rate limited and deduplicated per call site by `plog_limit`.
+
+use crate::plog_limit;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    collections::VecDeque,
+    ffi::c_char,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError, RwLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
 
 /// Callback to log.
 ///
@@ -13,74 +38,326 @@ pub const __LOG_LEVEL_INFO: &[u8; 5] = b"info\0";
 pub const __LOG_LEVEL_WARN: &[u8; 5] = b"warn\0";
 pub const __LOG_LEVEL_ERROR: &[u8; 6] = b"error\0";
 
-static mut LOG_CB: Option<CbLog> = None;
+static LOG_CB: RwLock<Option<CbLog>> = RwLock::new(None);
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Trace as usize);
+static LOGGER: PluginLogger = PluginLogger;
+static EARLY_LOG: Mutex<EarlyLog> = Mutex::new(EarlyLog::new(EARLY_LOG_CAPACITY));
+
+/// The max number of the lines kept before the callback is set, the oldest ones are dropped.
+pub const EARLY_LOG_CAPACITY: usize = 256;
+
+struct EarlyLine {
+    level: Level,
+    time: SystemTime,
+    msg: String,
+}
+
+/// The lines logged before the callback is set.
+struct EarlyLog {
+    capacity: usize,
+    lines: VecDeque<EarlyLine>,
+    // The lines dropped since the last flush.
+    dropped: usize,
+    // The lines dropped in total.
+    dropped_total: usize,
+}
+
+impl EarlyLog {
+    const fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lines: VecDeque::new(),
+            dropped: 0,
+            dropped_total: 0,
+        }
+    }
+
+    fn push(&mut self, level: Level, msg: &str) {
+        if self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+            self.dropped += 1;
+            self.dropped_total += 1;
+        }
+        self.lines.push_back(EarlyLine {
+            level,
+            time: SystemTime::now(),
+            msg: msg.to_owned(),
+        });
+    }
+
+    /// Take the lines to flush, prefixed by the time they were logged.
+    fn drain(&mut self) -> Vec<(Level, String)> {
+        let mut lines = Vec::with_capacity(self.lines.len() + 1);
+        if self.dropped > 0 {
+            lines.push((
+                Level::Warn,
+                format!("{} early log lines were dropped", self.dropped),
+            ));
+            self.dropped = 0;
+        }
+        lines.extend(self.lines.drain(..).map(|l| {
+            let time = l.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+            (
+                l.level,
+                format!(
+                    "[early {}.{:03}] {}",
+                    time.as_secs(),
+                    time.subsec_millis(),
+                    l.msg
+                ),
+            )
+        }));
+        lines
+    }
+}
+
+/// The `log` backend forwarding the records to the host log callback.
+pub struct PluginLogger;
+
+impl Log for PluginLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        flush_pending(Some(Instant::now()));
+        let msg = format!("[{}] {}", record.target(), record.args());
+        let lines =
+            plog_limit::check_record(record.file_static(), record.line(), record.level(), msg);
+        for line in lines {
+            emit(record.level(), &line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the `log` backend and set the max level.
+///
+/// Only one logger can be installed in the process, nothing is changed if another
+/// logger is already installed, except the max level.
+pub fn init_logger(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_err() {
+        log::debug!("Another logger is installed, the log records may not reach the host");
+    }
+    set_max_level(level);
+}
+
+/// Set the max level of both the macros of this module and the `log` crate.
+pub fn set_max_level(level: LevelFilter) {
+    MAX_LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level);
+}
+
+pub fn max_level() -> LevelFilter {
+    match MAX_LEVEL.load(Ordering::Relaxed) {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
 
+fn level_to_cstr(level: Level) -> &'static [u8] {
+    match level {
+        Level::Error => __LOG_LEVEL_ERROR,
+        Level::Warn => __LOG_LEVEL_WARN,
+        Level::Info => __LOG_LEVEL_INFO,
+        Level::Debug => __LOG_LEVEL_DEBUG,
+        Level::Trace => __LOG_LEVEL_TRACE,
+    }
+}
+
+// WARNING: this is not part of the crate's public API and is subject to change at any time
+pub fn __enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+// WARNING: this is not part of the crate's public API and is subject to change at any time
+/// Log without the rate limit.
+pub fn __log(level: Level, args: fmt::Arguments) {
+    if !__enabled(level) {
+        return;
+    }
+    flush_pending(Some(Instant::now()));
+    emit(level, &args.to_string());
+}
+
+// WARNING: this is not part of the crate's public API and is subject to change at any time
+/// Log with the rate limit and the deduplication of the call site.
+pub fn __log_at(site: &'static plog_limit::__Site, level: Level, args: fmt::Arguments) {
+    if !__enabled(level) {
+        return;
+    }
+    flush_pending(Some(Instant::now()));
+    for line in plog_limit::check_site(site, level, args.to_string()) {
+        emit(level, &line);
+    }
+}
+
+/// Write the counts of the rate limit not reported yet by their sites.
+fn flush_pending(now: Option<Instant>) {
+    for (level, line) in plog_limit::flush_pending(now) {
+        if __enabled(level) {
+            emit(level, &line);
+        }
+    }
+}
+
+fn emit(level: Level, msg: &str) {
+    #[cfg(feature = "tracing")]
+    if crate::ptrace::is_installed() {
+        crate::ptrace::event(level, format_args!("{}", msg));
+        return;
+    }
+    write_log(level, msg);
+}
+
+/// Send the line to the host log callback, buffered if the callback is not set.
+pub(crate) fn write_log(level: Level, msg: &str) {
+    let cb = __get_log().or_else(|| {
+        let mut early = EARLY_LOG.lock().unwrap_or_else(PoisonError::into_inner);
+        // Check again under the lock, `set_log` may have flushed the buffer meanwhile.
+        let cb = __get_log();
+        if cb.is_none() {
+            early.push(level, msg);
+        }
+        cb
+    });
+    crate::plog_file::write(level, msg, cb.is_some());
+    if let Some(cb) = cb {
+        call_cb(cb, level, msg);
+    }
+}
+
+/// Write the line of a `tracing` event, rate limited by its file and line.
+#[cfg(feature = "tracing")]
+pub(crate) fn write_log_at(
+    file: Option<&'static str>,
+    line: Option<u32>,
+    level: Level,
+    msg: String,
+) {
+    for line in plog_limit::check_record(file, line, level, msg) {
+        write_log(level, &line);
+    }
+}
+
+fn call_cb(cb: CbLog, level: Level, msg: &str) {
+    let mut s = msg.to_owned();
+    s.push('\0');
+    cb(level_to_cstr(level).as_ptr() as _, s.as_ptr() as _);
+}
+
+/// Set the host log callback, and flush the lines logged before.
+///
+/// The buffered lines above `max_level` are dropped.
 pub fn set_log(cb: CbLog) {
-    unsafe {
-        LOG_CB = Some(cb);
+    let lines = {
+        let mut early = EARLY_LOG.lock().unwrap_or_else(PoisonError::into_inner);
+        *LOG_CB.write().unwrap_or_else(PoisonError::into_inner) = Some(cb);
+        early.drain()
+    };
+    for (level, msg) in lines {
+        if __enabled(level) {
+            call_cb(cb, level, &msg);
+        }
     }
+    flush_pending(None);
+}
+
+/// The lines buffered before the callback is set, they are kept for the callback.
+pub(crate) fn early_lines() -> Vec<(Level, SystemTime, String)> {
+    EARLY_LOG
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .lines
+        .iter()
+        .map(|l| (l.level, l.time, l.msg.clone()))
+        .collect()
+}
+
+/// The number of the lines dropped because the buffer was full before the callback was set.
+pub fn dropped_lines() -> usize {
+    EARLY_LOG
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .dropped_total
+}
+
+/// Unbind the host log callback, the counts of the rate limit are reported before.
+pub fn clear_log() {
+    flush_pending(None);
+    *LOG_CB.write().unwrap_or_else(PoisonError::into_inner) = None;
 }
 
 // WARNING: this is not part of the crate's public API and is subject to change at any time
-pub fn __get_log() -> &'static Option<CbLog> {
-    unsafe { &LOG_CB }
+pub fn __get_log() -> Option<CbLog> {
+    *LOG_CB.read().unwrap_or_else(PoisonError::into_inner)
 }
 
 #[macro_export]
 macro_rules! log_level {
-    ($level: ident, $lvl:ident, $($arg:tt)*) => {
-        match $crate::plog::__get_log() {
-            Some(cb) => {
-                let mut s = format!($($arg)*);
-                s.push('\0');
-                cb(
-                    $crate::plog::$lvl.as_ptr() as _,
-                    s.as_ptr() as _,
-                );
-            },
-            None => log::$level!($($arg)*),
-        }
-    };
+    ($Level:ident, $($arg:tt)*) => {{
+        static __SITE: $crate::plog_limit::__Site = $crate::plog_limit::__Site::new();
+        $crate::plog::__log_at(&__SITE, $crate::log::Level::$Level, format_args!($($arg)*))
+    }};
 }
 
 #[macro_export]
 macro_rules! trace {
     ($($arg:tt)*) => {
-        $crate::log_level!(trace, __LOG_LEVEL_TRACE, $($arg)*);
+        $crate::log_level!(Trace, $($arg)*);
     };
 }
 
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {
-        $crate::log_level!(debug, __LOG_LEVEL_DEBUG, $($arg)*);
+        $crate::log_level!(Debug, $($arg)*);
     };
 }
 
 #[macro_export]
 macro_rules! info {
     ($($arg:tt)*) => {
-        $crate::log_level!(info, __LOG_LEVEL_INFO, $($arg)*);
+        $crate::log_level!(Info, $($arg)*);
     };
 }
 
 #[macro_export]
 macro_rules! warn {
     ($($arg:tt)*) => {
-        $crate::log_level!(warn, __LOG_LEVEL_WARN, $($arg)*);
+        $crate::log_level!(Warn, $($arg)*);
     };
 }
 
 #[macro_export]
 macro_rules! error {
     ($($arg:tt)*) => {
-        $crate::log_level!(error, __LOG_LEVEL_ERROR, $($arg)*);
+        $crate::log_level!(Error, $($arg)*);
     };
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    // The tests share the callback, the callback is global.
+    pub(crate) static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static CB_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Serialize the tests changing or relying on the callback, a line logged while another
+    /// test clears the callback would be buffered as an early line.
+    pub(crate) fn lock_cb() -> MutexGuard<'static, ()> {
+        CB_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+    }
 
     #[test]
     fn test_log_default() {
@@ -95,10 +372,12 @@ mod tests {
         let level = unsafe { std::ffi::CStr::from_ptr(level).to_str().unwrap() };
         let msg = unsafe { std::ffi::CStr::from_ptr(msg).to_str().unwrap() };
         println!("{}: {}", level, msg);
+        LOGGED.lock().unwrap().push(format!("{}: {}", level, msg));
     }
 
     #[test]
     fn test_log_custom_print() {
+        let _lock = lock_cb();
         set_log(_log_cb);
         trace!("trace");
         debug!("debug");
@@ -106,4 +385,51 @@ mod tests {
         warn!("warn");
         error!("error");
     }
+
+    #[test]
+    fn test_log_crate() {
+        let _lock = lock_cb();
+        set_log(_log_cb);
+        init_logger(LevelFilter::Info);
+        log::info!("from log crate");
+        log::debug!("filtered from log crate");
+        debug!("filtered from plog");
+        let logged = LOGGED.lock().unwrap();
+        assert!(logged.contains(&"info: [plugin_common::plog::tests] from log crate".to_owned()));
+        assert!(!logged.iter().any(|l| l.contains("filtered")));
+    }
+
+    #[test]
+    fn test_log_early() {
+        let _lock = lock_cb();
+        clear_log();
+        warn!("logged before the callback");
+        set_log(_log_cb);
+        let logged = LOGGED.lock().unwrap();
+        assert!(
+            logged
+                .iter()
+                .any(|l| l.starts_with("warn: [early ")
+                    && l.ends_with("] logged before the callback"))
+        );
+    }
+
+    #[test]
+    fn test_early_log_dropped() {
+        let mut early = EarlyLog::new(2);
+        for i in 0..5 {
+            early.push(Level::Info, &i.to_string());
+        }
+        assert_eq!(early.dropped_total, 3);
+        let lines = early.drain();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            (Level::Warn, "3 early log lines were dropped".to_owned())
+        );
+        assert!(lines[1].1.ends_with("] 3"));
+        assert!(lines[2].1.ends_with("] 4"));
+        assert!(early.drain().is_empty());
+        assert_eq!(early.dropped_total, 3);
+    }
 }
@@ -0,0 +1,225 @@
+//! The local log file, a fallback and a mirror of the host log callback.
+//!
+//! The lines are written to `plugin.log` in the directory, with the time, level and thread id.
+//! The file is rotated by size, `plugin.log.1` is the newest rotated file and the oldest ones
+//! are removed.
+//!
+//! In `FileMode::Fallback`, only the lines logged while the host log callback is not set are
+//! written. In `FileMode::Mirror`, all the lines are written. The lines buffered before the host
+//! log callback is set are written once the file is set.
+
+use log::Level;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub const LOG_FILE_NAME: &str = "plugin.log";
+pub const DEFAULT_MAX_SIZE: u64 = 5 * 1024 * 1024;
+pub const DEFAULT_MAX_FILES: usize = 3;
+
+static FILE_LOG: Mutex<Option<FileLog>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMode {
+    /// Write the lines only when the host log callback is not set.
+    Fallback,
+    /// Write all the lines.
+    Mirror,
+}
+
+/// The size-rotated log file.
+pub struct FileLog {
+    dir: PathBuf,
+    mode: FileMode,
+    max_size: u64,
+    max_files: usize,
+    // Opened at the first line, nothing is created if nothing is logged.
+    file: Option<File>,
+    size: u64,
+}
+
+impl FileLog {
+    pub fn new<P: AsRef<Path>>(dir: P, mode: FileMode) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+            mode,
+            max_size: DEFAULT_MAX_SIZE,
+            max_files: DEFAULT_MAX_FILES,
+            file: None,
+            size: 0,
+        }
+    }
+
+    /// The max size of a file, the file is rotated before exceeding it.
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// The max number of the rotated files to keep, besides the current one.
+    pub fn max_files(mut self, max_files: usize) -> Self {
+        self.max_files = max_files;
+        self
+    }
+
+    #[inline]
+    pub fn path(&self) -> PathBuf {
+        self.dir.join(LOG_FILE_NAME)
+    }
+
+    fn write(&mut self, time: SystemTime, level: Level, msg: &str) -> io::Result<()> {
+        let line = format!(
+            "{} {:<5} [{:?}] {}\n",
+            format_time(time),
+            level,
+            std::thread::current().id(),
+            msg
+        );
+        if self.file.is_some() && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            fs::create_dir_all(&self.dir)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.path())?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            self.size += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let path = self.path();
+        let rotated = |i: usize| PathBuf::from(format!("{}.{}", path.display(), i));
+        if self.max_files == 0 {
+            return fs::remove_file(&path);
+        }
+        fs::remove_file(rotated(self.max_files)).ok();
+        for i in (1..self.max_files).rev() {
+            fs::rename(rotated(i), rotated(i + 1)).ok();
+        }
+        fs::rename(&path, rotated(1))
+    }
+}
+
+/// Set the log file, the previous one is closed.
+pub fn set_file_log(mut file_log: FileLog) {
+    for (level, time, msg) in crate::plog::early_lines() {
+        file_log.write(time, level, &msg).ok();
+    }
+    *FILE_LOG.lock().unwrap_or_else(PoisonError::into_inner) = Some(file_log);
+}
+
+/// Change the mode of the log file, nothing is done if the log file is not set.
+pub fn set_file_mode(mode: FileMode) {
+    if let Some(f) = FILE_LOG
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_mut()
+    {
+        f.mode = mode;
+    }
+}
+
+pub fn clear_file_log() {
+    *FILE_LOG.lock().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+/// The path of the current log file, `None` if the log file is not set.
+pub fn file_log_path() -> Option<PathBuf> {
+    FILE_LOG
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_ref()
+        .map(|f| f.path())
+}
+
+/// Write the line to the log file if it is set and the mode accepts it.
+pub(crate) fn write(level: Level, msg: &str, has_cb: bool) {
+    let mut file_log = FILE_LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(f) = file_log.as_mut() {
+        if f.mode == FileMode::Mirror || !has_cb {
+            // The log file is best effort, the error can't be logged.
+            f.write(SystemTime::now(), level, msg).ok();
+        }
+    }
+}
+
+/// Format the time as `2023-02-03 13:05:02.123` in UTC.
+fn format_time(time: SystemTime) -> String {
+    let d = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        d.subsec_millis()
+    )
+}
+
+/// The date of the days since 1970-01-01, http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_time() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_675_429_502_123);
+        assert_eq!(format_time(t), "2023-02-03 13:05:02.123");
+        assert_eq!(format_time(UNIX_EPOCH), "1970-01-01 00:00:00.000");
+        let t = UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(format_time(t), "2000-02-29 00:00:00.000");
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("plog_file_test_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut f = FileLog::new(&dir, FileMode::Mirror)
+            .max_size(100)
+            .max_files(2);
+        for i in 0..10 {
+            f.write(SystemTime::now(), Level::Info, &format!("line {}", i))
+                .unwrap();
+        }
+        let content = fs::read_to_string(f.path()).unwrap();
+        assert!(content.ends_with(" line 9\n"));
+        assert!(content.contains(" INFO  [ThreadId("));
+        assert!(fs::metadata(f.path()).unwrap().len() <= 100);
+        assert!(dir.join("plugin.log.1").exists());
+        assert!(dir.join("plugin.log.2").exists());
+        assert!(!dir.join("plugin.log.3").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,316 @@
+//! The rate limit and the deduplication of the log lines, per call site.
+//!
+//! Each call site of the `plog` macros, and each file and line of the `log` crate records, may
+//! log at most `rate_limit(level)` lines per second, the rest are counted. The same line logged
+//! again by the same site is collapsed and counted. The counts are reported by the site once a
+//! different line is logged or the next second starts, by the next line of any site once the
+//! second of the site is over, and when the host log callback is set or cleared.
+
+use log::Level;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+/// The default max lines per second of a call site, of all the levels.
+pub const DEFAULT_RATE_LIMIT: u32 = 100;
+const WINDOW: Duration = Duration::from_secs(1);
+
+// Indexed by `Level as usize - 1`, 0 means unlimited.
+static RATE_LIMITS: [AtomicU32; 5] = [
+    AtomicU32::new(DEFAULT_RATE_LIMIT),
+    AtomicU32::new(DEFAULT_RATE_LIMIT),
+    AtomicU32::new(DEFAULT_RATE_LIMIT),
+    AtomicU32::new(DEFAULT_RATE_LIMIT),
+    AtomicU32::new(DEFAULT_RATE_LIMIT),
+];
+static DEDUP: AtomicBool = AtomicBool::new(true);
+static RECORD_SITES: Mutex<Option<HashMap<(&'static str, u32), SiteState>>> = Mutex::new(None);
+// The sites with the counts not reported yet.
+static PENDING_SITES: Mutex<Vec<SiteKey>> = Mutex::new(Vec::new());
+
+/// Set the max lines per second of a call site at the level, 0 means unlimited.
+pub fn set_rate_limit(level: Level, lines_per_sec: u32) {
+    RATE_LIMITS[level as usize - 1].store(lines_per_sec, Ordering::Relaxed);
+}
+
+pub fn rate_limit(level: Level) -> u32 {
+    RATE_LIMITS[level as usize - 1].load(Ordering::Relaxed)
+}
+
+/// Enable or disable collapsing the repeated lines, enabled by default.
+pub fn set_dedup(enabled: bool) {
+    DEDUP.store(enabled, Ordering::Relaxed);
+}
+
+// WARNING: this is not part of the crate's public API and is subject to change at any time
+/// The state of a call site of the `plog` macros.
+pub struct __Site(Mutex<SiteState>);
+
+impl __Site {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> Self {
+        Self(Mutex::new(SiteState::new()))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SiteKey {
+    Macro(&'static __Site),
+    Record(&'static str, u32),
+}
+
+impl SiteKey {
+    fn is(&self, other: &SiteKey) -> bool {
+        match (self, other) {
+            (Self::Macro(a), Self::Macro(b)) => std::ptr::eq(*a, *b),
+            (Self::Record(f1, l1), Self::Record(f2, l2)) => f1 == f2 && l1 == l2,
+            _ => false,
+        }
+    }
+}
+
+struct SiteState {
+    window_start: Option<Instant>,
+    lines: u32,
+    suppressed: u32,
+    // The last line written, the counts are reported with it.
+    last: Option<String>,
+    repeated: u32,
+    level: Level,
+}
+
+impl SiteState {
+    const fn new() -> Self {
+        Self {
+            window_start: None,
+            lines: 0,
+            suppressed: 0,
+            last: None,
+            repeated: 0,
+            level: Level::Trace,
+        }
+    }
+
+    /// Push the lines to write to `out`, the line itself and the pending reports.
+    fn check(&mut self, now: Instant, limit: u32, dedup: bool, msg: String, out: &mut Vec<String>) {
+        if self.is_due(now) {
+            self.report(out);
+            self.window_start = Some(now);
+            self.lines = 0;
+        }
+        if dedup && self.last.as_ref() == Some(&msg) {
+            self.repeated += 1;
+            return;
+        }
+        self.flush_repeated(out);
+        if limit > 0 && self.lines >= limit {
+            self.suppressed += 1;
+        } else {
+            self.lines += 1;
+            out.push(msg.clone());
+            self.last = Some(msg);
+        }
+    }
+
+    /// The second of the site is over.
+    fn is_due(&self, now: Instant) -> bool {
+        self.window_start
+            .is_none_or(|start| now.duration_since(start) >= WINDOW)
+    }
+
+    #[inline]
+    fn has_pending(&self) -> bool {
+        self.repeated > 0 || self.suppressed > 0
+    }
+
+    /// Push the reports of the counts to `out`.
+    fn report(&mut self, out: &mut Vec<String>) {
+        self.flush_repeated(out);
+        if self.suppressed > 0 {
+            let last = self.last.as_deref().unwrap_or_default();
+            out.push(if self.suppressed == 1 {
+                format!("1 line after '{}' was suppressed by the rate limit", last)
+            } else {
+                format!(
+                    "{} lines after '{}' were suppressed by the rate limit",
+                    self.suppressed, last
+                )
+            });
+            self.suppressed = 0;
+        }
+    }
+
+    fn flush_repeated(&mut self, out: &mut Vec<String>) {
+        if self.repeated > 0 {
+            let last = self.last.as_deref().unwrap_or_default();
+            out.push(if self.repeated == 1 {
+                format!("'{}' repeated once", last)
+            } else {
+                format!("'{}' repeated {} times", last, self.repeated)
+            });
+            self.repeated = 0;
+        }
+    }
+}
+
+/// The lines to write for the message of the macro call site.
+pub(crate) fn check_site(site: &'static __Site, level: Level, msg: String) -> Vec<String> {
+    let mut out = Vec::with_capacity(1);
+    let limit = rate_limit(level);
+    let dedup = DEDUP.load(Ordering::Relaxed);
+    let pending = {
+        let mut s = site.0.lock().unwrap_or_else(PoisonError::into_inner);
+        s.level = level;
+        s.check(Instant::now(), limit, dedup, msg, &mut out);
+        s.has_pending()
+    };
+    if pending {
+        add_pending(SiteKey::Macro(site));
+    }
+    out
+}
+
+/// The lines to write for the `log` crate record, the records without the static file are
+/// not limited.
+pub(crate) fn check_record(
+    file: Option<&'static str>,
+    line: Option<u32>,
+    level: Level,
+    msg: String,
+) -> Vec<String> {
+    let (Some(file), Some(line)) = (file, line) else {
+        return vec![msg];
+    };
+    let mut out = Vec::with_capacity(1);
+    let limit = rate_limit(level);
+    let dedup = DEDUP.load(Ordering::Relaxed);
+    let pending = {
+        let mut sites = RECORD_SITES.lock().unwrap_or_else(PoisonError::into_inner);
+        let s = sites
+            .get_or_insert_with(HashMap::new)
+            .entry((file, line))
+            .or_insert_with(SiteState::new);
+        s.level = level;
+        s.check(Instant::now(), limit, dedup, msg, &mut out);
+        s.has_pending()
+    };
+    if pending {
+        add_pending(SiteKey::Record(file, line));
+    }
+    out
+}
+
+// Called out of the lock of the site, `flush_pending` locks the list first.
+fn add_pending(key: SiteKey) {
+    let mut sites = PENDING_SITES.lock().unwrap_or_else(PoisonError::into_inner);
+    if !sites.iter().any(|k| k.is(&key)) {
+        sites.push(key);
+    }
+}
+
+/// Take the reports of the counts of all the sites, with the levels of the sites.
+///
+/// now: Only the sites whose second is over are reported, all of them if `None`.
+pub(crate) fn flush_pending(now: Option<Instant>) -> Vec<(Level, String)> {
+    let mut out = Vec::new();
+    PENDING_SITES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|key| {
+            let mut report = |s: &mut SiteState| {
+                if now.is_none_or(|now| s.is_due(now)) {
+                    let mut lines = Vec::new();
+                    s.report(&mut lines);
+                    out.extend(lines.into_iter().map(|l| (s.level, l)));
+                }
+                s.has_pending()
+            };
+            match key {
+                SiteKey::Macro(site) => {
+                    report(&mut site.0.lock().unwrap_or_else(PoisonError::into_inner))
+                }
+                SiteKey::Record(file, line) => RECORD_SITES
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .as_mut()
+                    .and_then(|sites| sites.get_mut(&(*file, *line)))
+                    .is_some_and(report),
+            }
+        });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(s: &mut SiteState, now: Instant, limit: u32, msg: &str) -> Vec<String> {
+        let mut out = vec![];
+        s.check(now, limit, true, msg.to_owned(), &mut out);
+        out
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut s = SiteState::new();
+        let now = Instant::now();
+        for i in 0..5 {
+            let out = check(&mut s, now, 3, &i.to_string());
+            assert_eq!(out.len(), if i < 3 { 1 } else { 0 });
+        }
+        let out = check(&mut s, now + WINDOW, 3, "5");
+        assert_eq!(
+            out,
+            ["2 lines after '2' were suppressed by the rate limit", "5"]
+        );
+        check(&mut s, now + WINDOW, 3, "6");
+        check(&mut s, now + WINDOW, 3, "7");
+        check(&mut s, now + WINDOW, 3, "8");
+        assert_eq!(
+            check(&mut s, now + WINDOW * 2, 3, "9"),
+            ["1 line after '7' was suppressed by the rate limit", "9"]
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut s = SiteState::new();
+        let now = Instant::now();
+        assert_eq!(check(&mut s, now, 0, "a"), ["a"]);
+        for _ in 0..1000 {
+            assert!(check(&mut s, now, 0, "a").is_empty());
+        }
+        assert_eq!(check(&mut s, now, 0, "b"), ["'a' repeated 1000 times", "b"]);
+        check(&mut s, now, 0, "b");
+        // Reported at the next window, the line is still collapsed.
+        assert_eq!(check(&mut s, now + WINDOW, 0, "b"), ["'b' repeated once"]);
+
+        let mut out = vec![];
+        s.check(now + WINDOW, 0, false, "b".to_owned(), &mut out);
+        s.check(now + WINDOW, 0, false, "b".to_owned(), &mut out);
+        assert_eq!(out, ["'b' repeated once", "b", "b"]);
+    }
+
+    #[test]
+    fn test_flush_pending() {
+        static SITE: __Site = __Site::new();
+        // `set_log` and `clear_log` of the other tests flush all the sites.
+        let _lock = crate::plog::tests::lock_cb();
+        let msg = "flushed by another site";
+        assert_eq!(check_site(&SITE, Level::Info, msg.to_owned()), [msg]);
+        assert!(check_site(&SITE, Level::Info, msg.to_owned()).is_empty());
+        let report = (Level::Info, format!("'{}' repeated once", msg));
+        // Not reported by the other sites until the second of the site is over.
+        assert!(!flush_pending(Some(Instant::now())).contains(&report));
+        assert!(flush_pending(Some(Instant::now() + WINDOW)).contains(&report));
+        assert!(!flush_pending(None).contains(&report));
+
+        assert!(check_site(&SITE, Level::Info, msg.to_owned()).is_empty());
+        assert!(flush_pending(None).contains(&report));
+    }
+}
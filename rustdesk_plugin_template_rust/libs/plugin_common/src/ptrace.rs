@@ -0,0 +1,253 @@
+//! `tracing` support, the events are forwarded to the host log callback.
+//!
+//! Each line is prefixed by the spans of the event, from the root, for example
+//! `plugin_call{method=handle_ui peer=123}:plugin{id=Test}: turn on`.
+//! The levels are filtered by `plog::max_level`, checked at each event, so the changes of the
+//! level apply to the call sites already hit. The events of `tracing` macros are rate limited
+//! by their file and line, like the `log` crate records.
+
+use crate::plog;
+use std::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    Layer,
+};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+// The target of the lines of the `plog` macros and the `log` crate, already rate limited.
+const PLOG_TARGET: &str = "plog";
+
+/// The layer forwarding the events to the host log callback.
+pub struct CbLogLayer;
+
+/// The formatted fields of the span, stored in the span extensions.
+struct SpanFields(String);
+
+#[derive(Default)]
+struct FieldsVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldsVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.push_str(value);
+        } else {
+            self.push_field(field, format_args!("{}", value));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.message, "{:?}", value).ok();
+        } else {
+            self.push_field(field, format_args!("{:?}", value));
+        }
+    }
+}
+
+impl FieldsVisitor {
+    fn push_field(&mut self, field: &Field, value: fmt::Arguments) {
+        if !self.fields.is_empty() {
+            self.fields.push(' ');
+        }
+        write!(self.fields, "{}={}", field.name(), value).ok();
+    }
+}
+
+impl<S> Layer<S> for CbLogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        // Not cached, the max level may change.
+        if metadata.is_span() {
+            Interest::always()
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        // The spans are always kept, they are the context of the enabled events.
+        metadata.is_span() || plog::__enabled(to_log_level(*metadata.level()))
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldsVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            let mut visitor = FieldsVisitor {
+                fields: std::mem::take(&mut fields.0),
+                ..Default::default()
+            };
+            values.record(&mut visitor);
+            fields.0 = visitor.fields;
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let level = to_log_level(*metadata.level());
+        if !plog::__enabled(level) {
+            return;
+        }
+        let mut line = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                line.push_str(span.name());
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    if !fields.0.is_empty() {
+                        write!(line, "{{{}}}", fields.0).ok();
+                    }
+                }
+                line.push(':');
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+        }
+        let mut visitor = FieldsVisitor::default();
+        event.record(&mut visitor);
+        line.push_str(&visitor.message);
+        if !visitor.fields.is_empty() {
+            write!(line, " {}", visitor.fields).ok();
+        }
+        if metadata.target() == PLOG_TARGET {
+            plog::write_log(level, &line);
+        } else {
+            plog::write_log_at(metadata.file(), metadata.line(), level, line);
+        }
+    }
+}
+
+/// Install the global subscriber with `CbLogLayer`.
+///
+/// Nothing is changed if another global subscriber is already installed.
+pub fn init_tracing() {
+    let subscriber = tracing_subscriber::registry().with(CbLogLayer);
+    if tracing::subscriber::set_global_default(subscriber).is_ok() {
+        INSTALLED.store(true, Ordering::SeqCst);
+    }
+}
+
+#[inline]
+pub fn is_installed() -> bool {
+    INSTALLED.load(Ordering::SeqCst)
+}
+
+/// Send the line of the `plog` macros or the `log` crate as a `tracing` event.
+pub(crate) fn event(level: log::Level, args: fmt::Arguments) {
+    match level {
+        log::Level::Error => tracing::error!(target: PLOG_TARGET, "{}", args),
+        log::Level::Warn => tracing::warn!(target: PLOG_TARGET, "{}", args),
+        log::Level::Info => tracing::info!(target: PLOG_TARGET, "{}", args),
+        log::Level::Debug => tracing::debug!(target: PLOG_TARGET, "{}", args),
+        log::Level::Trace => tracing::trace!(target: PLOG_TARGET, "{}", args),
+    }
+}
+
+fn to_log_level(level: Level) -> log::Level {
+    match level {
+        Level::ERROR => log::Level::Error,
+        Level::WARN => log::Level::Warn,
+        Level::INFO => log::Level::Info,
+        Level::DEBUG => log::Level::Debug,
+        Level::TRACE => log::Level::Trace,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plog::tests::{_log_cb, lock_cb, LOGGED};
+
+    #[test]
+    fn test_tracing() {
+        let _lock = lock_cb();
+        plog::set_log(_log_cb);
+        init_tracing();
+        assert!(is_installed());
+        let span = tracing::info_span!(
+            "plugin_call",
+            method = "handle_ui",
+            plugin = tracing::field::Empty
+        );
+        span.in_scope(|| {
+            tracing::Span::current().record("plugin", "Test");
+            tracing::info_span!("inner").in_scope(|| {
+                crate::info!("from plog");
+                tracing::warn!(count = 2, "from tracing");
+            });
+        });
+        let logged = LOGGED.lock().unwrap();
+        assert!(logged.contains(
+            &"info: plugin_call{method=handle_ui plugin=Test}:inner: from plog".to_owned()
+        ));
+        assert!(logged.contains(
+            &"warn: plugin_call{method=handle_ui plugin=Test}:inner: from tracing count=2"
+                .to_owned()
+        ));
+    }
+
+    #[test]
+    fn test_tracing_level() {
+        let _lock = lock_cb();
+        plog::set_log(_log_cb);
+        init_tracing();
+        let prev = plog::max_level();
+        let emit = |i: usize| tracing::debug!("level changed {}", i);
+        plog::set_max_level(log::LevelFilter::Info);
+        emit(1);
+        plog::set_max_level(log::LevelFilter::Debug);
+        emit(2);
+        plog::set_max_level(log::LevelFilter::Info);
+        emit(3);
+        plog::set_max_level(prev);
+        let logged = LOGGED.lock().unwrap();
+        let lines = logged
+            .iter()
+            .filter(|l| l.contains("level changed"))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["debug: level changed 2"]);
+    }
+
+    #[test]
+    fn test_tracing_rate_limit() {
+        let _lock = lock_cb();
+        plog::set_log(_log_cb);
+        init_tracing();
+        for _ in 0..3 {
+            tracing::warn!("repeated by tracing");
+        }
+        let logged = LOGGED.lock().unwrap();
+        assert_eq!(
+            logged
+                .iter()
+                .filter(|l| l.ends_with("repeated by tracing"))
+                .count(),
+            1
+        );
+    }
+}
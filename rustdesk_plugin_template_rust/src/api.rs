@@ -1,31 +1,43 @@
-use plugin_base::{init::InitData, str_to_cstr_ret, PluginReturn};
+use plugin_base::{guard::catch_panic, init::InitData, str_to_cstr_ret, PluginReturn};
 use std::ffi::{c_char, c_void};
-use std::ptr::null_mut;
+use std::ptr::{null, null_mut};
 
 extern "C" {}
 
 #[no_mangle]
 pub extern "C" fn init(info: *const InitData) -> PluginReturn {
-    plugin_base::init::init(
-        Box::new(super::call::HandlerTemplate {}),
-        super::desc::get_desc(),
-        info,
-    )
+    catch_panic("init", || {
+        plugin_base::init::init(
+            Box::new(super::call::HandlerTemplate {}),
+            super::desc::get_desc(),
+            info,
+        )
+    })
 }
 
 #[no_mangle]
 pub extern "C" fn reset(info: *const InitData) -> PluginReturn {
-    plugin_base::init::reset(info)
+    catch_panic("reset", || plugin_base::init::reset(info))
 }
 
 #[no_mangle]
 pub extern "C" fn clear() -> PluginReturn {
-    plugin_base::init::clear()
+    catch_panic("clear", plugin_base::init::clear)
 }
 
 #[no_mangle]
 pub extern "C" fn desc() -> *const c_char {
-    str_to_cstr_ret(&super::desc::get_desc_string())
+    plugin_base::guard::catch_panic_or("desc", null(), || {
+        str_to_cstr_ret(&super::desc::get_desc_string())
+    })
+}
+
+/// The descs of the logical plugins, a json array.
+#[no_mangle]
+pub extern "C" fn descs() -> *const c_char {
+    plugin_base::guard::catch_panic_or("descs", null(), || {
+        str_to_cstr_ret(&super::desc::get_descs_string())
+    })
 }
 
 #[no_mangle]
@@ -35,7 +47,9 @@ pub extern "C" fn client_call(
     args: *const c_void,
     len: usize,
 ) -> PluginReturn {
-    plugin_base::call::plugin_call(method, peer, args, len, null_mut(), null_mut())
+    catch_panic("client_call", || {
+        plugin_base::call::plugin_call(method, peer, args, len, null_mut(), null_mut())
+    })
 }
 
 #[no_mangle]
@@ -47,5 +61,22 @@ pub extern "C" fn server_call(
     out: *mut *mut c_void,
     out_len: *mut usize,
 ) -> PluginReturn {
-    plugin_base::call::plugin_call(method, peer, args, len, out, out_len)
+    catch_panic("server_call", || {
+        plugin_base::call::plugin_call(method, peer, args, len, out, out_len)
+    })
+}
+
+/// Free the memory returned by the plugin: `PluginReturn.msg`, the result of `desc()`
+/// and the `out` buffer of `server_call`.
+#[no_mangle]
+pub extern "C" fn plugin_free(ptr: *mut c_void) {
+    plugin_base::guard::catch_panic_or("plugin_free", (), || plugin_base::mem::free(ptr))
+}
+
+/// The number of the buffers returned by the plugin and not freed by `plugin_free` yet.
+///
+/// Only tracked with the `mem-track` feature, always 0 otherwise.
+#[no_mangle]
+pub extern "C" fn plugin_mem_outstanding() -> usize {
+    plugin_base::guard::catch_panic_or("plugin_mem_outstanding", 0, plugin_base::mem::outstanding)
 }
@@ -1,18 +1,20 @@
 use super::desc;
 use plugin_base::{
-    cstr_to_string,
+    call::get_conf,
+    context::get_context,
     desc::{Desc, CONFIG_VALUE_FALSE, CONFIG_VALUE_TRUE},
     early_return_if_true, early_return_value,
     errno::*,
+    error::{HandlerResult, PluginError},
     handler::*,
-    init::get_init_data,
+    method::Methods,
+    migration::{Migrations, Step},
 };
 use plugin_common::{
-    libc, log,
     serde_derive::{Deserialize, Serialize},
-    serde_json, ResultType,
+    serde_json,
 };
-use std::ffi::{c_char, c_void};
+use std::ffi::c_void;
 
 const MSG_PEER_METHOD_TURN_ON: &str = "on";
 const MSG_PEER_METHOD_TURN_OFF: &str = "off";
@@ -30,50 +32,15 @@ impl PluginPeerMsg {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct MsgPeer {
-    pub id: String,
-    pub name: String,
-    pub method: String,
-    pub content: String,
+#[derive(Debug, Deserialize)]
+pub(crate) struct GetStatusArgs {
+    key: String,
 }
 
-impl MsgPeer {
-    #[inline]
-    pub fn new_string(d: &Desc, method: String, content: String) -> String {
-        let mut s = serde_json::to_string(&MsgPeer {
-            id: d.id.clone(),
-            name: d.name.clone(),
-            method,
-            content,
-        })
-        .unwrap();
-        // Add trailing 0 to make it a C string in this case
-        s.push('\0');
-        s
-    }
-
-    #[inline]
-    pub fn fill_out(
-        d: &Desc,
-        method: String,
-        content: String,
-        out: *mut *mut c_void,
-        out_len: *mut usize,
-    ) {
-        let s = Self::new_string(d, method, content);
-        let b = s.as_bytes();
-        unsafe {
-            *out = libc::malloc(b.len());
-            libc::memcpy(*out, b.as_ptr() as _, b.len());
-            *out_len = b.len();
-        }
-    }
-
-    #[inline]
-    pub fn from_c_str(msg: *const c_char) -> ResultType<Self> {
-        Ok(serde_json::from_str(&cstr_to_string(msg)?)?)
-    }
+#[derive(Debug, Serialize)]
+pub(crate) struct Status {
+    key: String,
+    value: Option<String>,
 }
 
 pub struct HandlerTemplate;
@@ -81,49 +48,47 @@ pub struct HandlerTemplate;
 impl Handler for HandlerTemplate {
     fn handle_ui_event(&self, d: &Desc, local_peer_id: String, msg_ui: MsgFromUi) -> HandlerRet {
         let mut ret = HandlerRet::default();
-        match &msg_ui.location as _ {
-            desc::UI_CLIENT_REMOTE_LOCATION => match &msg_ui.key as _ {
-                desc::UI_CLIENT_REMOTE_KEY => match &msg_ui.value as _ {
-                    CONFIG_VALUE_TRUE | CONFIG_VALUE_FALSE => {
-                        ret.code = ERR_SUCCESS;
-                        ret.msg = "".to_string();
+        if msg_ui.location == desc::LOCATION_CLIENT_REMOTE_TOOLBAR_DISPLAY
+            && msg_ui.key == desc::KEY_PEER_OPT
+        {
+            match &msg_ui.value as _ {
+                CONFIG_VALUE_TRUE | CONFIG_VALUE_FALSE => {
+                    ret.code = ERR_SUCCESS;
+                    ret.msg = "".to_string();
 
-                        let turn_on_off = if &msg_ui.value == CONFIG_VALUE_FALSE {
-                            MSG_PEER_METHOD_TURN_OFF
-                        } else {
-                            MSG_PEER_METHOD_TURN_ON
-                        };
-                        let msg_peer_content = PluginPeerMsg::new_string(local_peer_id);
-                        ret.msgs.to_peer.push(MsgPeer::new_string(
-                            d,
-                            turn_on_off.to_string(),
-                            msg_peer_content,
-                        ));
-                    }
-                    _ => {}
-                },
+                    let turn_on_off = if msg_ui.value == CONFIG_VALUE_FALSE {
+                        MSG_PEER_METHOD_TURN_OFF
+                    } else {
+                        MSG_PEER_METHOD_TURN_ON
+                    };
+                    let msg_peer_content = PluginPeerMsg::new_string(local_peer_id);
+                    ret.msgs.to_peer.push(MsgPeer::new_string(
+                        d,
+                        turn_on_off.to_string(),
+                        msg_peer_content,
+                    ));
+                }
                 _ => {}
-            },
-            desc::UI_HOST_MAIN_LOCATION => match &msg_ui.key as _ {
-                desc::UI_HOST_MAIN_KEY => match &msg_ui.value as _ {
-                    CONFIG_VALUE_TRUE | CONFIG_VALUE_FALSE => {
-                        ret.code = ERR_SUCCESS;
-                        ret.msg = "".to_string();
-                        ret.msgs.to_config.push(MsgToConfig::new_string(
-                            CONFIG_TYPE_SHARED.to_string(),
-                            desc::UI_HOST_MAIN_KEY.to_owned(),
-                            msg_ui.value,
-                            Some(ConfigToUi {
-                                channel: MSG_TO_UI_FLUTTER_CHANNEL_MAIN,
-                                location: desc::UI_HOST_MAIN_LOCATION.to_owned(),
-                            }),
-                        ));
-                    }
-                    _ => {}
-                },
+            }
+        } else if msg_ui.location == desc::LOCATION_HOST_MAIN_SETTINGS_PLUGIN
+            && msg_ui.key == desc::KEY_ALLOW_OPT
+        {
+            match &msg_ui.value as _ {
+                CONFIG_VALUE_TRUE | CONFIG_VALUE_FALSE => {
+                    ret.code = ERR_SUCCESS;
+                    ret.msg = "".to_string();
+                    ret.msgs.to_config.push(MsgToConfig::new_string(
+                        CONFIG_TYPE_SHARED.to_string(),
+                        desc::KEY_ALLOW_OPT.to_owned(),
+                        msg_ui.value,
+                        Some(ConfigToUi {
+                            channel: MSG_TO_UI_FLUTTER_CHANNEL_MAIN,
+                            location: desc::LOCATION_HOST_MAIN_SETTINGS_PLUGIN.to_owned(),
+                        }),
+                    ));
+                }
                 _ => {}
-            },
-            _ => {}
+            }
         }
 
         ret
@@ -133,78 +98,16 @@ impl Handler for HandlerTemplate {
         &self,
         d: &Desc,
         args: *const c_void,
-        _len: usize,
+        len: usize,
         out: *mut *mut c_void,
         out_len: *mut usize,
     ) -> HandlerRet {
-        let msg_peer = early_return_value!(
-            MsgPeer::from_c_str(args as _),
-            ERR_CALL_INVALID_ARGS,
-            "parse args"
-        );
-
-        early_return_if_true!(
-            msg_peer.id != d.id,
-            ERR_PEER_ID_MISMATCH,
-            "Id mismatch {}",
-            msg_peer.id
-        );
-
-        let mut ret = HandlerRet::default();
-        match &msg_peer.method as &str {
-            MSG_PEER_METHOD_TURN_ON => {
-                let args = early_return_value!(
-                    serde_json::from_str::<PluginPeerMsg>(&msg_peer.content as _),
-                    ERR_CALL_INVALID_ARGS,
-                    "parse msg content {}",
-                    msg_peer.content
-                );
-                // process on event
-                println!("Plugin: process event {:?}", &args);
-                ret.code = ERR_SUCCESS;
-                ret.msg = "".to_owned();
-                // ret.code = EER_CALL_FAILED;
-                // ret.msg = "something error".to_string();
-                MsgPeer::fill_out(
-                    d,
-                    MSG_PEER_METHOD_NOTIFY_TURN_ON.to_string(),
-                    ret.msg.clone(),
-                    out,
-                    out_len,
-                );
-            }
-            MSG_PEER_METHOD_TURN_OFF => {
-                let args = early_return_value!(
-                    serde_json::from_str::<PluginPeerMsg>(&msg_peer.content as _),
-                    ERR_CALL_INVALID_ARGS,
-                    "parse msg content {}",
-                    msg_peer.content
-                );
-                // process on event
-                println!("Plugin: process event {:?}", &args);
-                ret.code = ERR_SUCCESS;
-                ret.msg = "".to_owned();
-                // ret.code = EER_CALL_FAILED;
-                // ret.msg = "something error".to_string();
-                MsgPeer::fill_out(
-                    d,
-                    MSG_PEER_METHOD_NOTIFY_TURN_OFF.to_string(),
-                    ret.msg.clone(),
-                    out,
-                    out_len,
-                );
-            }
-            _ => {
-                ret.code = ERR_CALL_INVALID_ARGS;
-                ret.msg = format!("Invalid method {}", msg_peer.method);
-            }
-        }
-        ret
+        Self::client_event(d, args, len, out, out_len).into()
     }
 
-    fn handle_server_event(&self, d: &Desc, args: *const c_void, _len: usize) -> HandlerRet {
+    fn handle_server_event(&self, d: &Desc, args: *const c_void, len: usize) -> HandlerRet {
         let msg_peer = early_return_value!(
-            MsgPeer::from_c_str(args as _),
+            MsgPeer::from_args(args, len),
             ERR_CALL_INVALID_ARGS,
             "parse args"
         );
@@ -272,6 +175,22 @@ impl Handler for HandlerTemplate {
         ret
     }
 
+    fn register_methods(&self, methods: &mut Methods) {
+        methods.register(desc::METHOD_GET_STATUS, Self::get_status);
+    }
+
+    fn register_migrations(&self, migrations: &mut Migrations) {
+        // Version 1, `allow-opt` is "1" or "0", the older values "true" and "false" are converted.
+        migrations.add(
+            1,
+            vec![Step::transform(desc::KEY_ALLOW_OPT, |v| match v {
+                "true" => CONFIG_VALUE_TRUE.to_owned(),
+                "false" => CONFIG_VALUE_FALSE.to_owned(),
+                _ => v.to_owned(),
+            })],
+        );
+    }
+
     fn handle_listen_event(
         &self,
         _d: &Desc,
@@ -283,7 +202,7 @@ impl Handler for HandlerTemplate {
         match &event.event as _ {
             EVENT_ON_CONN_CLIENT => {
                 // Get config if some options should be turned on
-                if let Some(_data) = get_init_data().lock().unwrap().as_ref() {
+                if let Some(_ctx) = get_context() {
                 } else {
                     ret.code = ERR_PLUGIN_MSG_INIT;
                     ret.msg = "Plugin is not initialized".to_owned();
@@ -302,15 +221,62 @@ impl Handler for HandlerTemplate {
 }
 
 impl HandlerTemplate {
+    /// Get the config value, the peer config of the remote peer or the shared config.
+    fn get_status(d: &Desc, peer: &str, args: GetStatusArgs) -> Result<Status, PluginError> {
+        let peer = match &args.key as &str {
+            desc::KEY_PEER_OPT => peer,
+            desc::KEY_ALLOW_OPT => "",
+            _ => {
+                return Err(PluginError::InvalidArgs(format!(
+                    "Unknown key {}",
+                    args.key
+                )))
+            }
+        };
+        Ok(Status {
+            value: get_conf(&d.id, peer, &args.key),
+            key: args.key,
+        })
+    }
+
+    fn client_event(
+        d: &Desc,
+        args: *const c_void,
+        len: usize,
+        out: *mut *mut c_void,
+        out_len: *mut usize,
+    ) -> HandlerResult {
+        let msg_peer = MsgPeer::from_args(args, len)?;
+        if msg_peer.id != d.id {
+            return Err(PluginError::PeerIdMismatch(msg_peer.id));
+        }
+
+        let notify_method = match &msg_peer.method as &str {
+            MSG_PEER_METHOD_TURN_ON => MSG_PEER_METHOD_NOTIFY_TURN_ON,
+            MSG_PEER_METHOD_TURN_OFF => MSG_PEER_METHOD_NOTIFY_TURN_OFF,
+            _ => {
+                return Err(PluginError::InvalidArgs(format!(
+                    "Invalid method {}",
+                    msg_peer.method
+                )))
+            }
+        };
+        let args = serde_json::from_str::<PluginPeerMsg>(&msg_peer.content)?;
+        // process on/off event
+        plugin_common::debug!("Plugin: process event {:?}", &args);
+        MsgPeer::fill_out(d, notify_method.to_string(), "".to_owned(), out, out_len);
+        Ok(HandlerRet::success())
+    }
+
     #[inline]
     fn make_msg_to_config(v: &str) -> String {
         MsgToConfig::new_string(
             CONFIG_TYPE_PEER.to_string(),
-            desc::UI_CLIENT_REMOTE_KEY.to_owned(),
+            desc::KEY_PEER_OPT.to_owned(),
             v.to_owned(),
             Some(ConfigToUi {
                 channel: MSG_TO_UI_FLUTTER_CHANNEL_REMOTE,
-                location: desc::UI_CLIENT_REMOTE_LOCATION.to_owned(),
+                location: desc::LOCATION_CLIENT_REMOTE_TOOLBAR_DISPLAY.to_owned(),
             }),
         )
     }
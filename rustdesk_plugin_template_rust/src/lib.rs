@@ -6,23 +6,52 @@ mod desc;
 mod tests {
     use dlopen::symbor::Library;
     use plugin_base::{desc::Desc, init::InitData, str_to_cstr_ret, Callbacks, PluginReturn};
-    use plugin_common::{bail, libc, log, serde_json, ResultType};
-    use std::ffi::{c_char, c_void, CStr};
-
-    #[inline]
-    fn free_c_ptr(ret: *mut c_void) {
-        if !ret.is_null() {
-            unsafe {
-                libc::free(ret);
-            }
-        }
+    use plugin_common::{bail, serde_json, ResultType};
+    use std::{
+        ffi::{c_char, c_void, CStr},
+        mem::ManuallyDrop,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Mutex,
+        },
+        time::Duration,
+    };
+
+    type ClientCall = extern "C" fn(
+        method: *const c_char,
+        peer: *const c_char,
+        args: *const c_void,
+        len: usize,
+    ) -> PluginReturn;
+    type ServerCall = extern "C" fn(
+        method: *const c_char,
+        peer: *const c_char,
+        args: *const c_void,
+        len: usize,
+        out: *mut *mut c_void,
+        out_len: *mut usize,
+    ) -> PluginReturn;
+
+    /// The plugin functions used by the host callbacks to call back into the plugin.
+    #[derive(Clone, Copy)]
+    struct ReentryFns {
+        client_call: ClientCall,
+        server_call: ServerCall,
+        plugin_free: extern "C" fn(*mut c_void),
     }
 
+    static REENTRY_FNS: Mutex<Option<ReentryFns>> = Mutex::new(None);
+    static REENTRY_COUNT: AtomicUsize = AtomicUsize::new(0);
+    // The shared config of the host, by key.
+    static SHARED_CONF: Mutex<Vec<(&str, &str)>> = Mutex::new(Vec::new());
+
     macro_rules! make_plugin {
         ($($field:ident : $tp:ty),+) => {
             #[allow(dead_code)]
             struct Plugin {
-                _lib: Library,
+                // Never unloaded, the threads which called into the plugin run its
+                // thread-local destructors on exit.
+                _lib: ManuallyDrop<Library>,
                 id: Option<String>,
                 path: String,
                 $($field: $tp),+
@@ -49,7 +78,7 @@ mod tests {
                     ;)+
 
                     Ok(Self {
-                        _lib: lib,
+                        _lib: ManuallyDrop::new(lib),
                         id: None,
                         path: path.to_string(),
                         $( $field ),+
@@ -60,14 +89,32 @@ mod tests {
                     let desc_ret = (self.desc)();
                     let s = unsafe { CStr::from_ptr(desc_ret as _) };
                     let desc = serde_json::from_str(s.to_str()?);
-                    free_c_ptr(desc_ret as _);
+                    (self.plugin_free)(desc_ret as _);
                     Ok(desc?)
                 }
 
+                fn descs(&self) -> ResultType<Vec<Desc>> {
+                    let descs_ret = (self.descs)();
+                    let s = unsafe { CStr::from_ptr(descs_ret as _) };
+                    let descs = serde_json::from_str(s.to_str()?);
+                    (self.plugin_free)(descs_ret as _);
+                    Ok(descs?)
+                }
+
+                /// Take the code and msg, the msg is freed by the plugin, so the leak check works.
+                fn code_msg(&self, ret: PluginReturn) -> (i32, String) {
+                    if ret.is_success() {
+                        return (ret.code, "".to_owned());
+                    }
+                    let msg = plugin_base::cstr_to_string(ret.msg).unwrap_or_default();
+                    (self.plugin_free)(ret.msg as _);
+                    (ret.code, msg)
+                }
+
                 fn init(&self, data: &InitData, path: &str) -> ResultType<()> {
-                    let mut init_ret = (self.init)(data as _);
+                    let init_ret = (self.init)(data as _);
                     if !init_ret.is_success() {
-                        let (code, msg) = init_ret.get_code_msg();
+                        let (code, msg) = self.code_msg(init_ret);
                         bail!(
                             "Failed to init plugin {}, code: {}, msg: {}",
                             path,
@@ -79,9 +126,9 @@ mod tests {
                 }
 
                 fn clear(&self, id: &str) {
-                    let mut clear_ret = (self.clear)();
+                    let clear_ret = (self.clear)();
                     if !clear_ret.is_success() {
-                        let (code, msg) = clear_ret.get_code_msg();
+                        let (code, msg) = self.code_msg(clear_ret);
                         plugin_common::error!(
                             "Failed to clear plugin {}, code: {}, msg: {}",
                             id,
@@ -103,47 +150,76 @@ mod tests {
 
     make_plugin!(
         init: extern "C" fn(*const InitData) -> PluginReturn,
-        reset: extern "C" fn() -> PluginReturn,
+        reset: extern "C" fn(*const InitData) -> PluginReturn,
         clear: extern "C" fn() -> PluginReturn,
         desc: extern "C" fn() -> *const c_void,
-        client_call:
-            extern "C" fn(
-                method: *const c_char,
-                peer: *const c_char,
-                args: *const c_void,
-                len: usize,
-            ) -> PluginReturn,
-        server_call:
-            extern "C" fn(
-                method: *const c_char,
-                peer: *const c_char,
-                args: *const c_void,
-                len: usize,
-                out: *mut *mut c_void,
-                out_len: *mut usize,
-            ) -> PluginReturn
+        descs: extern "C" fn() -> *const c_void,
+        client_call: ClientCall,
+        server_call: ServerCall,
+        plugin_free: extern "C" fn(*mut c_void),
+        plugin_mem_outstanding: extern "C" fn() -> usize
     );
 
     #[no_mangle]
     extern "C" fn msg(
-        _peer: *const c_char,
-        _target: *const c_char,
+        peer: *const c_char,
+        target: *const c_char,
         _id: *const c_char,
-        _content: *const c_void,
-        _len: usize,
+        content: *const c_void,
+        len: usize,
     ) -> PluginReturn {
         println!("msg called");
+        let target = unsafe { CStr::from_ptr(target) };
+        // Copy the functions out of the lock, the plugin may call `msg` again.
+        let fns = *REENTRY_FNS.lock().unwrap();
+        if let (b"peer", Some(fns)) = (target.to_bytes(), fns) {
+            // Deliver the message to the "remote" plugin and the reply back, synchronously,
+            // from inside the callback.
+            let mut out = std::ptr::null_mut();
+            let mut out_len: usize = 0;
+            let ret = (fns.server_call)(
+                c"handle_peer".as_ptr() as _,
+                peer,
+                content,
+                len,
+                &mut out,
+                &mut out_len,
+            );
+            if !ret.is_success() {
+                return ret;
+            }
+            let ret = (fns.client_call)(c"handle_peer".as_ptr() as _, peer, out, out_len);
+            (fns.plugin_free)(out);
+            if !ret.is_success() {
+                return ret;
+            }
+            REENTRY_COUNT.fetch_add(1, Ordering::SeqCst);
+        }
         PluginReturn::success()
     }
 
     #[no_mangle]
     extern "C" fn get_conf(
-        _peer: *const c_char,
+        peer: *const c_char,
         _id: *const c_char,
-        _key: *const c_char,
+        key: *const c_char,
     ) -> *const c_char {
         println!("get_conf called");
-        std::ptr::null()
+        let peer = unsafe { CStr::from_ptr(peer) };
+        let key = unsafe { CStr::from_ptr(key) }.to_str().unwrap();
+        if !peer.is_empty() {
+            return std::ptr::null();
+        }
+        match SHARED_CONF.lock().unwrap().iter().find(|(k, _)| *k == key) {
+            Some((_, v)) => str_to_cstr_ret(v),
+            None => std::ptr::null(),
+        }
+    }
+
+    fn set_shared_conf(key: &'static str, value: &'static str) {
+        let mut conf = SHARED_CONF.lock().unwrap();
+        conf.retain(|(k, _)| *k != key);
+        conf.push((key, value));
     }
 
     #[no_mangle]
@@ -171,19 +247,39 @@ mod tests {
     }
 
     #[test]
+    #[cfg_attr(feature = "mem-track", ignore = "run by test_plugin_leaks")]
     fn test_plugin() {
+        run_plugin();
+    }
+
+    /// All the buffers returned by the plugin must be freed.
+    ///
+    /// Build the library and run the test with `--features mem-track`, the buffers are not
+    /// counted without the feature.
+    #[test]
+    #[cfg_attr(not(feature = "mem-track"), ignore = "requires --features mem-track")]
+    fn test_plugin_leaks() {
+        let plugin = run_plugin();
+        assert_eq!((plugin.plugin_mem_outstanding)(), 0, "leaked buffers");
+    }
+
+    /// Run the calls of the host, the plugin is cleared at the end.
+    fn run_plugin() -> Plugin {
         #[cfg(target_os = "windows")]
-        let lib_ext = "dll";
+        let lib_name = "plugin_template.dll";
         #[cfg(target_os = "linux")]
-        let lib_ext = "so";
+        let lib_name = "libplugin_template.so";
         #[cfg(target_os = "macos")]
-        let lib_ext = "dylib";
-        let path = format!("target/debug/plugin_template.{}", lib_ext);
+        let lib_name = "libplugin_template.dylib";
+        let path = format!("target/debug/{}", lib_name);
         let plugin = Plugin::new(&path).unwrap();
-        let _desc = plugin.desc().unwrap();
+        let desc = plugin.desc().unwrap();
+        let descs = plugin.descs().unwrap();
+        assert_eq!(descs.len(), 1);
+        assert_eq!(descs[0].id, desc.id);
         // println!("{:?}", desc);
         let init_data = InitData {
-            version: str_to_cstr_ret("test version"),
+            version: str_to_cstr_ret("1.2.0"),
             cbs: Callbacks {
                 msg,
                 get_conf,
@@ -192,9 +288,18 @@ mod tests {
                 native,
             },
         };
+        let old_host_data = InitData {
+            version: str_to_cstr_ret("1.1.0"),
+            cbs: init_data.cbs,
+        };
+        assert!(plugin.init(&old_host_data, &path).is_err());
         plugin.init(&init_data, &path).unwrap();
+        set_shared_conf(
+            super::desc::KEY_ALLOW_OPT,
+            plugin_base::desc::CONFIG_VALUE_TRUE,
+        );
         let args_content = crate::call::PluginPeerMsg::new_string("local peer id".to_owned());
-        let mut args = super::call::MsgPeer::new_string(
+        let mut args = plugin_base::handler::MsgPeer::new_string(
             &super::desc::get_desc(),
             "on".to_owned(),
             args_content,
@@ -202,21 +307,319 @@ mod tests {
         args.push('\0');
         let mut out = std::ptr::null_mut();
         let mut out_len: usize = 0;
-        let mut ret = (plugin.server_call)(
-            "handle_peer\0".as_ptr() as _,
-            "remote peer id\0".as_ptr() as _,
+        let ret = (plugin.server_call)(
+            c"handle_peer".as_ptr() as _,
+            c"remote peer id".as_ptr() as _,
             args.as_bytes().as_ptr() as _,
-            args.as_bytes().len(),
+            args.len(),
             &mut out,
             &mut out_len,
         );
         if ret.is_success() {
             println!("call success");
         } else {
-            let (code, msg) = ret.get_code_msg();
+            let (code, msg) = plugin.code_msg(ret);
             println!("code: {}, msg: {}", code, msg);
         }
+        (plugin.plugin_free)(out);
+
+        test_reentry(&plugin);
+        test_peer_policy(&plugin);
+        test_json_errors(&plugin);
+        test_custom_method(&plugin);
+        test_stats(&plugin);
+        test_diagnostics(&plugin);
+
+        assert!((plugin.reset)(&init_data).is_success());
 
         std::thread::sleep(std::time::Duration::from_secs(3));
+
+        plugin.clear(&path);
+        plugin
+    }
+
+    fn test_custom_method(plugin: &Plugin) {
+        let desc = super::desc::get_desc();
+        assert_eq!(
+            desc.methods,
+            vec![super::desc::METHOD_GET_STATUS.to_owned()]
+        );
+        let call = |key: &str| {
+            let args = serde_json::json!({"id": desc.id, "content": {"key": key}}).to_string();
+            let mut out = std::ptr::null_mut();
+            let mut out_len: usize = 0;
+            let ret = (plugin.server_call)(
+                c"get_status".as_ptr() as _,
+                c"remote peer id".as_ptr() as _,
+                args.as_ptr() as _,
+                args.len(),
+                &mut out,
+                &mut out_len,
+            );
+            let output = (!out.is_null()).then(|| {
+                let s = unsafe { CStr::from_ptr(out as _) }
+                    .to_str()
+                    .unwrap()
+                    .to_owned();
+                (plugin.plugin_free)(out);
+                s
+            });
+            (plugin.code_msg(ret), output)
+        };
+
+        let ((code, msg), output) = call(super::desc::KEY_ALLOW_OPT);
+        assert_eq!(code, plugin_base::errno::ERR_SUCCESS, "{}", msg);
+        let output: serde_json::Value = serde_json::from_str(&output.unwrap()).unwrap();
+        assert_eq!(
+            output,
+            serde_json::json!({"key": super::desc::KEY_ALLOW_OPT, "value": "1"})
+        );
+
+        let ((code, _), output) = call("unknown-opt");
+        assert_eq!(code, plugin_base::errno::ERR_CALL_INVALID_ARGS);
+        assert!(output.is_none());
+
+        // The unknown methods are refused whatever the args are.
+        let args = b"\xffnot json";
+        let mut out = std::ptr::null_mut();
+        let mut out_len: usize = 0;
+        let ret = (plugin.server_call)(
+            c"unknown_method".as_ptr() as _,
+            c"remote peer id".as_ptr() as _,
+            args.as_ptr() as _,
+            args.len(),
+            &mut out,
+            &mut out_len,
+        );
+        let (code, msg) = plugin.code_msg(ret);
+        assert_eq!(
+            code,
+            plugin_base::errno::ERR_CALL_NOT_SUPPORTED_METHOD,
+            "{}",
+            msg
+        );
+        assert!(out.is_null());
+    }
+
+    /// The host reads the metrics of the calls made above.
+    fn test_stats(plugin: &Plugin) {
+        let get_stats = |args: &CStr| {
+            let mut out = std::ptr::null_mut();
+            let mut out_len: usize = 0;
+            let ret = (plugin.server_call)(
+                c"get_stats".as_ptr() as _,
+                c"".as_ptr() as _,
+                args.as_ptr() as _,
+                args.count_bytes(),
+                &mut out,
+                &mut out_len,
+            );
+            let (code, msg) = plugin.code_msg(ret);
+            assert_eq!(code, plugin_base::errno::ERR_SUCCESS, "{}", msg);
+            let s = unsafe { CStr::from_ptr(out as _) }
+                .to_str()
+                .unwrap()
+                .to_owned();
+            (plugin.plugin_free)(out);
+            s
+        };
+
+        let stats: serde_json::Value = serde_json::from_str(&get_stats(c"")).unwrap();
+        let get_status = &stats["methods"][super::desc::METHOD_GET_STATUS];
+        assert_eq!(get_status["calls"], 2);
+        let invalid_args = plugin_base::errno::ERR_CALL_INVALID_ARGS.to_string();
+        assert_eq!(get_status["errors"][&invalid_args], 1);
+        assert_eq!(get_status["latency_us"]["count"], 2);
+        assert!(stats["peers"]["remote peer id"]["calls"].as_u64().unwrap() >= 2);
+
+        let text = get_stats(c"{\"format\": \"prometheus\"}");
+        assert!(text.contains("plugin_method_calls_total{method=\"get_stats\"} 1\n"));
+    }
+
+    /// The report reflects the state of the plugin and the failed calls above.
+    fn test_diagnostics(plugin: &Plugin) {
+        let mut out = std::ptr::null_mut();
+        let mut out_len: usize = 0;
+        let ret = (plugin.server_call)(
+            c"get_diagnostics".as_ptr() as _,
+            c"".as_ptr() as _,
+            std::ptr::null(),
+            0,
+            &mut out,
+            &mut out_len,
+        );
+        let (code, msg) = plugin.code_msg(ret);
+        assert_eq!(code, plugin_base::errno::ERR_SUCCESS, "{}", msg);
+        let report: serde_json::Value =
+            serde_json::from_str(unsafe { CStr::from_ptr(out as _) }.to_str().unwrap()).unwrap();
+        (plugin.plugin_free)(out);
+
+        let desc = super::desc::get_desc();
+        assert_eq!(report["initialized"], true);
+        assert_eq!(report["host_version"], "1.2.0");
+        assert_eq!(report["plugins"][0]["id"], desc.id);
+        assert_eq!(report["plugins"][0]["version"], desc.version);
+        assert_eq!(report["pending_calls"], 0);
+        assert_eq!(report["log_bound"], true);
+        assert_eq!(report["poisoned"], false);
+        let errors = report["last_errors"].as_array().unwrap();
+        assert!(errors
+            .iter()
+            .any(|e| e["method"] == super::desc::METHOD_GET_STATUS
+                && e["code"] == plugin_base::errno::ERR_CALL_INVALID_ARGS
+                && e["peer"] == "remote peer id"));
+    }
+
+    /// The remote peer can't turn the option on if it is not allowed by the host.
+    fn test_peer_policy(plugin: &Plugin) {
+        let call_turn_on = || {
+            let args = plugin_base::handler::MsgPeer::new_string(
+                &super::desc::get_desc(),
+                "on".to_owned(),
+                crate::call::PluginPeerMsg::new_string("local peer id".to_owned()),
+            );
+            let mut out = std::ptr::null_mut();
+            let mut out_len: usize = 0;
+            let ret = (plugin.server_call)(
+                c"handle_peer".as_ptr() as _,
+                c"remote peer id".as_ptr() as _,
+                args.as_ptr() as _,
+                args.len(),
+                &mut out,
+                &mut out_len,
+            );
+            (plugin.plugin_free)(out);
+            plugin.code_msg(ret)
+        };
+
+        let (code, msg) = call_turn_on();
+        assert_eq!(code, plugin_base::errno::ERR_SUCCESS, "{}", msg);
+
+        set_shared_conf(
+            super::desc::KEY_ALLOW_OPT,
+            plugin_base::desc::CONFIG_VALUE_FALSE,
+        );
+        let (code, msg) = call_turn_on();
+        assert_eq!(
+            code,
+            plugin_base::errno::ERR_PEER_PERMISSION_DENIED,
+            "{}",
+            msg
+        );
+        set_shared_conf(
+            super::desc::KEY_ALLOW_OPT,
+            plugin_base::desc::CONFIG_VALUE_TRUE,
+        );
+    }
+
+    /// The errors are sent as JSON after the host announces the support.
+    fn test_json_errors(plugin: &Plugin) {
+        let call_invalid_method = || {
+            let args = plugin_base::handler::MsgPeer::new_string(
+                &super::desc::get_desc(),
+                "invalid".to_owned(),
+                "".to_owned(),
+            );
+            let mut out = std::ptr::null_mut();
+            let mut out_len: usize = 0;
+            let ret = (plugin.server_call)(
+                c"handle_peer".as_ptr() as _,
+                c"remote peer id".as_ptr() as _,
+                args.as_ptr() as _,
+                args.len(),
+                &mut out,
+                &mut out_len,
+            );
+            plugin.code_msg(ret)
+        };
+
+        // The method without a declared policy is refused.
+        let (code, msg) = call_invalid_method();
+        assert_eq!(code, plugin_base::errno::ERR_PEER_PERMISSION_DENIED);
+        assert!(serde_json::from_str::<serde_json::Value>(&msg).is_err());
+
+        let features = c"{\"json_errors\": true}";
+        let ret = (plugin.client_call)(
+            c"set_host_features".as_ptr() as _,
+            c"".as_ptr() as _,
+            features.as_ptr() as _,
+            features.count_bytes(),
+        );
+        assert!(ret.is_success());
+
+        let (code, msg) = call_invalid_method();
+        let details: serde_json::Value = serde_json::from_str(&msg).unwrap();
+        assert_eq!(details["code"], code);
+        assert_eq!(details["message"], "Permission denied");
+        assert_eq!(
+            details["context"][0],
+            "No policy of the peer method invalid"
+        );
+        assert_eq!(details["retryable"], false);
+
+        // The errors of the listen events and the built-in methods are also in JSON.
+        let client_call = |method: &CStr, args: &CStr| {
+            let ret = (plugin.client_call)(
+                method.as_ptr() as _,
+                c"remote peer id".as_ptr() as _,
+                args.as_ptr() as _,
+                args.count_bytes(),
+            );
+            let (code, msg) = plugin.code_msg(ret);
+            let details: serde_json::Value = serde_json::from_str(&msg).unwrap();
+            assert_eq!(details["code"], code);
+            (code, details)
+        };
+        let (code, details) = client_call(c"handle_listen_event", c"{\"event\": \"invalid\"}");
+        assert_eq!(code, plugin_base::errno::ERR_CALL_INVALID_ARGS);
+        assert_eq!(details["message"], "Invalid event invalid");
+        let (code, details) = client_call(c"set_host_features", c"not json");
+        assert_eq!(code, plugin_base::errno::ERR_CALL_INVALID_ARGS);
+        assert!(details["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Failed to parse host features"));
+    }
+
+    /// The host calls back into the plugin from inside the `msg` callback.
+    fn test_reentry(plugin: &Plugin) {
+        *REENTRY_FNS.lock().unwrap() = Some(ReentryFns {
+            client_call: plugin.client_call,
+            server_call: plugin.server_call,
+            plugin_free: plugin.plugin_free,
+        });
+        let desc = super::desc::get_desc();
+        let msg_ui = serde_json::json!({
+            "id": desc.id,
+            "name": desc.name,
+            "location": super::desc::LOCATION_CLIENT_REMOTE_TOOLBAR_DISPLAY.to_string(),
+            "key": super::desc::KEY_PEER_OPT,
+            "value": plugin_base::desc::CONFIG_VALUE_TRUE,
+            "action": "",
+        });
+        let args = format!("{}\0", msg_ui);
+        let client_call = plugin.client_call;
+        let (tx, rx) = mpsc::channel();
+        // A deadlock must fail the test instead of hanging it.
+        std::thread::spawn(move || {
+            let ret = client_call(
+                c"handle_ui".as_ptr() as _,
+                c"remote peer id".as_ptr() as _,
+                args.as_ptr() as _,
+                args.len(),
+            );
+            tx.send((ret.code, ret.msg as usize)).ok();
+        });
+        let (code, msg) = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("deadlock on re-entry");
+        *REENTRY_FNS.lock().unwrap() = None;
+        let ret = PluginReturn {
+            code,
+            msg: msg as _,
+        };
+        let (code, msg) = plugin.code_msg(ret);
+        assert_eq!(code, plugin_base::errno::ERR_SUCCESS, "{}", msg);
+        assert_eq!(REENTRY_COUNT.load(Ordering::SeqCst), 1);
     }
 }